@@ -51,5 +51,34 @@ fn secretbox_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, secretbox_benchmark);
+fn payload_size_benchmark(c: &mut Criterion) {
+    let sizes: [(&str, usize); 3] = [
+        ("1KB", 1024),
+        ("64KB", 64 * 1024),
+        ("16MB", 16 * 1024 * 1024),
+    ];
+    for &(name, size) in sizes.iter() {
+        c.bench_function(&format!("seal salsa20 poly1305 {}", name), move |b| {
+            let plaintext = vec![0u8; size];
+            let (salsa20, _) = SecretBox::from_random_key(&mut OsRng, CipherType::Salsa20);
+            let mut nonce = [0u8; 24];
+            OsRng.fill_bytes(&mut nonce);
+            b.iter(|| {
+                salsa20.seal(&plaintext[..], nonce);
+            })
+        });
+        c.bench_function(&format!("unseal salsa20 poly1305 {}", name), move |b| {
+            let plaintext = vec![0u8; size];
+            let (salsa20, _) = SecretBox::from_random_key(&mut OsRng, CipherType::Salsa20);
+            let mut nonce = [0u8; 24];
+            OsRng.fill_bytes(&mut nonce);
+            let sealed = salsa20.seal(&plaintext[..], nonce);
+            b.iter(|| {
+                salsa20.unseal(&sealed, nonce);
+            })
+        });
+    }
+}
+
+criterion_group!(benches, secretbox_benchmark, payload_size_benchmark);
 criterion_main!(benches);
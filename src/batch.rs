@@ -0,0 +1,162 @@
+//! Sealing and opening many messages at once
+//!
+//! The boxes of a batch are written back to back into a single buffer, so a batch needs a single
+//! allocation instead of one per message. The box of a message of `n` bytes is `n + 16` bytes
+//! long, so the position of each box follows from the lengths of the messages before it.
+//!
+//! The setup of a box (the HSalsa20 subkey and the first keystream block, which holds the
+//! Poly1305 key) is computed for `LANES` messages at once with the functions of the `lanes`
+//! module, and without allocating. With the `rayon` feature, these groups of messages are
+//! processed in parallel.
+use crate::lanes::LANES;
+use crate::SecretBox;
+
+/// Nonce of a single message
+pub type Nonce = [u8; 24];
+
+/// Output slices of a group of messages, together with the messages and their nonces
+type Group<'a, 'b> = (&'a mut [&'b mut [u8]], &'a [(&'b [u8], Nonce)]);
+
+/// Splits `out` into consecutive slices of the given lengths
+fn split_outputs<I>(mut out: &mut [u8], lens: I) -> Vec<&mut [u8]>
+where
+    I: Iterator<Item = usize>,
+{
+    let mut slices = Vec::with_capacity(lens.size_hint().0);
+    for len in lens {
+        let (head, tail) = out.split_at_mut(len);
+        slices.push(head);
+        out = tail;
+    }
+    slices
+}
+
+impl SecretBox {
+    /// Seals every message with its nonce and appends the boxes to `out`, in the same order as
+    /// the messages. Each box consists of the tag followed by the ciphertext, like the output of
+    /// `seal`.
+    pub fn seal_batch(&self, messages: &[(&[u8], Nonce)], out: &mut Vec<u8>) {
+        let start = out.len();
+        let total: usize = messages.iter().map(|(m, _)| m.len() + 16).sum();
+        out.resize(start + total, 0);
+        let mut slices = split_outputs(
+            &mut out[start..],
+            messages.iter().map(|(m, _)| m.len() + 16),
+        );
+        let seal_group = |(outputs, group): Group| {
+            let first_blocks = self.first_blocks(group);
+            for ((o, (m, _)), first) in outputs.iter_mut().zip(group).zip(first_blocks.iter()) {
+                let (tag, body) = o.split_at_mut(16);
+                tag.copy_from_slice(&self.seal_detached_from(*first, Some(m), body));
+            }
+        };
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            slices
+                .par_chunks_mut(LANES)
+                .zip(messages.par_chunks(LANES))
+                .for_each(seal_group);
+        }
+        #[cfg(not(feature = "rayon"))]
+        slices
+            .chunks_mut(LANES)
+            .zip(messages.chunks(LANES))
+            .for_each(seal_group);
+    }
+
+    /// Opens every box with its nonce and appends the messages to `out`, in the same order as
+    /// the boxes.
+    ///
+    /// Returns the index of the first box that could not be opened. In that case nothing is
+    /// appended to `out`.
+    pub fn open_batch(&self, boxes: &[(&[u8], Nonce)], out: &mut Vec<u8>) -> Result<(), usize> {
+        if let Some(i) = boxes.iter().position(|(b, _)| b.len() < 16) {
+            return Err(i);
+        }
+        let start = out.len();
+        let total: usize = boxes.iter().map(|(b, _)| b.len() - 16).sum();
+        out.resize(start + total, 0);
+        let mut slices = split_outputs(&mut out[start..], boxes.iter().map(|(b, _)| b.len() - 16));
+        // Returns the index of the first box in the group that could not be opened
+        let open_group = |(outputs, group): Group| {
+            let first_blocks = self.first_blocks(group);
+            outputs
+                .iter_mut()
+                .zip(group)
+                .zip(first_blocks.iter())
+                .position(|((o, (b, _)), first)| {
+                    let (tag, ciphertext) = b.split_at(16);
+                    self.open_detached_from(*first, tag, Some(ciphertext), o)
+                        .is_none()
+                })
+        };
+        #[cfg(feature = "rayon")]
+        let failed = {
+            use rayon::prelude::*;
+            slices
+                .par_chunks_mut(LANES)
+                .zip(boxes.par_chunks(LANES))
+                .enumerate()
+                .filter_map(|(g, group)| open_group(group).map(|i| g * LANES + i))
+                .min()
+        };
+        #[cfg(not(feature = "rayon"))]
+        let failed = slices
+            .chunks_mut(LANES)
+            .zip(boxes.chunks(LANES))
+            .enumerate()
+            .filter_map(|(g, group)| open_group(group).map(|i| g * LANES + i))
+            .next();
+        if let Some(i) = failed {
+            out.truncate(start);
+            return Err(i);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CipherType;
+
+    #[test]
+    fn batch() {
+        let s = SecretBox::new([3u8; 32], CipherType::Salsa20).unwrap();
+        let records: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; i]).collect();
+        let messages: Vec<(&[u8], Nonce)> = records
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (&r[..], [i as u8; 24]))
+            .collect();
+        let mut sealed = b"header".to_vec();
+        s.seal_batch(&messages, &mut sealed);
+
+        let mut boxes = Vec::new();
+        let mut offset = 6;
+        for (m, nonce) in messages.iter() {
+            let b = &sealed[offset..offset + m.len() + 16];
+            assert_eq!(b, &s.seal(m, *nonce)[..]);
+            boxes.push((b, *nonce));
+            offset += b.len();
+        }
+        assert_eq!(offset, sealed.len());
+
+        let mut opened = Vec::new();
+        s.open_batch(&boxes, &mut opened).unwrap();
+        assert_eq!(opened, records.concat());
+
+        let mut forged = sealed.clone();
+        // The third box starts after the header and the boxes of 0 and 1 bytes
+        let third = 6 + 16 + 17;
+        forged[third + 16] ^= 1;
+        let mut forged_boxes = boxes.clone();
+        forged_boxes[2].0 = &forged[third..third + 18];
+        let mut opened = b"keep".to_vec();
+        assert_eq!(s.open_batch(&forged_boxes, &mut opened), Err(2));
+        assert_eq!(opened, b"keep".to_vec());
+        forged_boxes[1].0 = &sealed[..15];
+        assert_eq!(s.open_batch(&forged_boxes, &mut opened), Err(1));
+    }
+}
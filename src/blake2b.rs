@@ -0,0 +1,212 @@
+//! The BLAKE2b hash function (RFC 7693)
+//!
+//! This implements keyed and unkeyed BLAKE2b with a variable output length of 1 to 64 bytes, as
+//! well as the salt and personalization parameters used by libsodium's `crypto_generichash`.
+//! It is used for deriving nonces of anonymous boxes and for key derivation.
+
+const IV: [u64; 8] = [
+    0x6a09_e667_f3bc_c908,
+    0xbb67_ae85_84ca_a73b,
+    0x3c6e_f372_fe94_f82b,
+    0xa54f_f53a_5f1d_36f1,
+    0x510e_527f_ade6_82d1,
+    0x9b05_688c_2b3e_6c1f,
+    0x1f83_d9ab_fb41_bd6b,
+    0x5be0_cd19_137e_2179,
+];
+
+const SIGMA: [[usize; 16]; 12] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+];
+
+#[inline(always)]
+fn g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+/// BLAKE2b hasher state
+#[derive(Clone)]
+pub struct Blake2b {
+    h: [u64; 8],
+    t: u128,
+    buf: [u8; 128],
+    buf_len: usize,
+    out_len: usize,
+}
+
+impl Blake2b {
+    /// Creates a new unkeyed hasher with an output length of `out_len` bytes.
+    ///
+    /// Panics if `out_len` is not between 1 and 64.
+    pub fn new(out_len: usize) -> Blake2b {
+        Self::with_params(out_len, &[], [0u8; 16], [0u8; 16])
+    }
+    /// Creates a new keyed hasher with an output length of `out_len` bytes.
+    ///
+    /// Panics if `out_len` is not between 1 and 64 or if the key is longer than 64 bytes.
+    pub fn new_keyed(out_len: usize, key: &[u8]) -> Blake2b {
+        Self::with_params(out_len, key, [0u8; 16], [0u8; 16])
+    }
+    /// Creates a new hasher with a key (which may be empty), a salt and a personalization string.
+    ///
+    /// Panics if `out_len` is not between 1 and 64 or if the key is longer than 64 bytes.
+    pub fn with_params(out_len: usize, key: &[u8], salt: [u8; 16], personal: [u8; 16]) -> Blake2b {
+        assert!((1..=64).contains(&out_len));
+        assert!(key.len() <= 64);
+        let mut h = IV;
+        h[0] ^= 0x0101_0000 ^ ((key.len() as u64) << 8) ^ (out_len as u64);
+        for i in 0..2 {
+            let mut s = [0u8; 8];
+            s.copy_from_slice(&salt[i * 8..(i + 1) * 8]);
+            h[4 + i] ^= u64::from_le_bytes(s);
+            s.copy_from_slice(&personal[i * 8..(i + 1) * 8]);
+            h[6 + i] ^= u64::from_le_bytes(s);
+        }
+        let mut state = Blake2b {
+            h,
+            t: 0,
+            buf: [0u8; 128],
+            buf_len: 0,
+            out_len,
+        };
+        if !key.is_empty() {
+            state.buf[..key.len()].copy_from_slice(key);
+            state.buf_len = 128;
+        }
+        state
+    }
+    fn compress(&mut self, last: bool) {
+        let mut m = [0u64; 16];
+        for (i, w) in m.iter_mut().enumerate() {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(&self.buf[i * 8..(i + 1) * 8]);
+            *w = u64::from_le_bytes(b);
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.t as u64;
+        v[13] ^= (self.t >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        for s in SIGMA.iter() {
+            g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+    /// Adds data to the hash
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block has to be processed by finalize, so a full buffer is only
+            // compressed once more data arrives
+            if self.buf_len == 128 {
+                self.t += 128;
+                self.compress(false);
+                self.buf_len = 0;
+            }
+            let n = std::cmp::min(128 - self.buf_len, data.len());
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+        }
+    }
+    /// Returns the finalized hash
+    pub fn finalize(mut self) -> Vec<u8> {
+        self.t += self.buf_len as u128;
+        for b in self.buf[self.buf_len..].iter_mut() {
+            *b = 0;
+        }
+        self.compress(true);
+        let mut out = Vec::with_capacity(64);
+        for w in self.h.iter() {
+            out.extend_from_slice(&w.to_le_bytes());
+        }
+        out.truncate(self.out_len);
+        out
+    }
+}
+
+/// Hashes a message with an optional key, returning `out_len` bytes
+pub fn blake2b(out_len: usize, key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state = Blake2b::new_keyed(out_len, key);
+    state.update(data);
+    state.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn rfc7693_vector() {
+        assert_eq!(
+            &blake2b(64, &[], b"abc")[..],
+            &[
+                0xba, 0x80, 0xa5, 0x3f, 0x98, 0x1c, 0x4d, 0x0d, 0x6a, 0x27, 0x97, 0xb6, 0x9f, 0x12,
+                0xf6, 0xe9, 0x4c, 0x21, 0x2f, 0x14, 0x68, 0x5a, 0xc4, 0xb7, 0x4b, 0x12, 0xbb, 0x6f,
+                0xdb, 0xff, 0xa2, 0xd1, 0x7d, 0x87, 0xc5, 0x39, 0x2a, 0xab, 0x79, 0x2d, 0xc2, 0x52,
+                0xd5, 0xde, 0x45, 0x33, 0xcc, 0x95, 0x18, 0xd3, 0x8a, 0xa8, 0xdb, 0xf1, 0x92, 0x5a,
+                0xb9, 0x23, 0x86, 0xed, 0xd4, 0x00, 0x99, 0x23
+            ][..]
+        );
+    }
+    #[test]
+    fn keyed_and_streamed() {
+        // Keyed BLAKE2b-256 of 0..255 with the key 0..63, fed in odd-sized pieces
+        let key: Vec<u8> = (0..64).collect();
+        let data: Vec<u8> = (0..=255).collect();
+        let mut state = Blake2b::new_keyed(32, &key);
+        for chunk in data.chunks(37) {
+            state.update(chunk);
+        }
+        let out = state.finalize();
+        assert_eq!(out, blake2b(32, &key, &data));
+        assert_eq!(
+            &out[..],
+            &[
+                0x1e, 0xe3, 0xb6, 0x31, 0x2b, 0x4e, 0x0f, 0x0b, 0x96, 0x63, 0xb8, 0x12, 0xb8, 0xc1,
+                0x29, 0xe6, 0xd4, 0x5c, 0x41, 0x0b, 0x1c, 0x9c, 0x5a, 0x16, 0x67, 0xbf, 0xc6, 0xdd,
+                0x95, 0x1d, 0xb7, 0x9f
+            ][..]
+        );
+    }
+    #[test]
+    fn salt_and_personal() {
+        let out =
+            Blake2b::with_params(16, &[], *b"saltsaltsaltsalt", *b"personalpersonal").finalize();
+        assert_eq!(
+            &out[..],
+            &[
+                0x3a, 0x06, 0x6a, 0x95, 0x9e, 0x43, 0xd3, 0x93, 0x30, 0x4b, 0xfe, 0xac, 0x54, 0x3e,
+                0x64, 0x5d
+            ][..]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Sealing and opening `bytes` buffers without copying
+//!
+//! `seal_bytes_mut` encrypts a `BytesMut` in place and `open_bytes_mut` returns the plaintext as
+//! a `Bytes` that shares the allocation of the box, so messages can be passed through a network
+//! stack built on the `bytes` crate without going through a `Vec`. The boxes have the same format
+//! as the output of `SecretBox::seal`.
+//!
+//! The tag is stored in front of the ciphertext. `seal_bytes_mut` makes room for it by moving the
+//! message. Callers that can write the message after `TAG_BYTES` bytes of headroom can use
+//! `seal_bytes_mut_after_headroom` instead, which never moves the message.
+use crate::SecretBox;
+use bytes::{Bytes, BytesMut};
+use std::fmt;
+
+/// Size of the tag in front of the ciphertext
+pub const TAG_BYTES: usize = 16;
+
+/// Error returned when a buffer is too short to hold the headroom for the tag
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct MissingHeadroom;
+
+impl fmt::Display for MissingHeadroom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "buffer is shorter than the headroom for the tag")
+    }
+}
+
+impl std::error::Error for MissingHeadroom {}
+
+impl SecretBox {
+    /// Seals the message in `buf` in place. Room for the tag is reserved in front of the
+    /// message, so afterwards `buf` holds the tag followed by the ciphertext.
+    pub fn seal_bytes_mut(&self, buf: &mut BytesMut, nonce: [u8; 24]) {
+        let len = buf.len();
+        buf.reserve(TAG_BYTES);
+        buf.resize(len + TAG_BYTES, 0);
+        buf.copy_within(..len, TAG_BYTES);
+        let (tag, body) = buf.split_at_mut(TAG_BYTES);
+        tag.copy_from_slice(&self.seal_detached(nonce, None, body));
+    }
+
+    /// Seals a message that the caller has written after `TAG_BYTES` bytes of headroom in
+    /// `buf`. The headroom is overwritten with the tag and the message is encrypted where it is.
+    ///
+    /// Returns an error if `buf` is shorter than `TAG_BYTES`
+    pub fn seal_bytes_mut_after_headroom(
+        &self,
+        buf: &mut BytesMut,
+        nonce: [u8; 24],
+    ) -> Result<(), MissingHeadroom> {
+        if buf.len() < TAG_BYTES {
+            return Err(MissingHeadroom);
+        }
+        let (tag, body) = buf.split_at_mut(TAG_BYTES);
+        tag.copy_from_slice(&self.seal_detached(nonce, None, body));
+        Ok(())
+    }
+
+    /// Opens a box in place. The tag is stripped and the plaintext is returned as a frozen
+    /// slice of the same allocation.
+    ///
+    /// Returns None if the box is not authentic. The buffer is overwritten with zeroes in that
+    /// case.
+    pub fn open_bytes_mut(&self, mut buf: BytesMut, nonce: [u8; 24]) -> Option<Bytes> {
+        if buf.len() < TAG_BYTES {
+            return None;
+        }
+        let (tag, body) = buf.split_at_mut(TAG_BYTES);
+        self.open_detached(nonce, tag, None, body)?;
+        Some(buf.split_off(TAG_BYTES).freeze())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CipherType;
+
+    #[test]
+    fn seal_open() {
+        let s = SecretBox::new([2u8; 32], CipherType::Salsa20).unwrap();
+        for &len in [0, 1, 15, 16, 17, 1000].iter() {
+            let message: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut buf = BytesMut::from(&message[..]);
+            s.seal_bytes_mut(&mut buf, [3u8; 24]);
+            assert_eq!(&buf[..], &s.seal(&message, [3u8; 24])[..]);
+            let plain = s.open_bytes_mut(buf, [3u8; 24]).unwrap();
+            assert_eq!(&plain[..], &message[..]);
+        }
+    }
+
+    #[test]
+    fn in_place() {
+        let s = SecretBox::new([2u8; 32], CipherType::Chacha20).unwrap();
+        let message: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut buf = BytesMut::with_capacity(TAG_BYTES + message.len());
+        buf.resize(TAG_BYTES, 0);
+        buf.extend_from_slice(&message);
+        let start = buf.as_ptr() as usize;
+        s.seal_bytes_mut_after_headroom(&mut buf, [3u8; 24])
+            .unwrap();
+        assert_eq!(&buf[..], &s.seal(&message, [3u8; 24])[..]);
+        // Sealing does not reallocate
+        assert_eq!(buf.as_ptr() as usize, start);
+        assert_eq!(
+            s.seal_bytes_mut_after_headroom(&mut BytesMut::from(&[0u8; 15][..]), [3u8; 24]),
+            Err(MissingHeadroom)
+        );
+
+        let mut forged = buf.clone();
+        forged[20] ^= 1;
+        assert_eq!(s.open_bytes_mut(forged, [3u8; 24]), None);
+        assert_eq!(
+            s.open_bytes_mut(BytesMut::from(&[0u8; 15][..]), [3u8; 24]),
+            None
+        );
+
+        let plain = s.open_bytes_mut(buf, [3u8; 24]).unwrap();
+        assert_eq!(&plain[..], &message[..]);
+        // The plaintext is a view into the original buffer
+        assert_eq!(plain.as_ptr() as usize, start + 16);
+    }
+}
@@ -45,9 +45,9 @@ impl XChacha20 {
             ],
         }
     }
-    /// Generates a single block of Salsa20 random data
-    pub fn generate_block(&mut self) -> Option<Vec<u8>> {
-        let input = [
+    /// Returns the input of the block function for the current counter value
+    pub(crate) fn state(&self) -> [u32; 16] {
+        [
             SIGMA[0],
             SIGMA[1],
             SIGMA[2],
@@ -64,7 +64,11 @@ impl XChacha20 {
             (self.ctr >> 32) as u32,
             self.nonce[0],
             self.nonce[1],
-        ];
+        ]
+    }
+    /// Generates a single block of Salsa20 random data
+    pub fn generate_block(&mut self) -> Option<Vec<u8>> {
+        let input = self.state();
         let output = chacha20(input);
         self.ctr = self.ctr.checked_add(1)?;
         let mut out_bytes = Vec::new();
@@ -0,0 +1,462 @@
+//! Chunked file format with random access
+//!
+//! A large message is split into chunks of a fixed size, and every chunk is sealed as an
+//! independent box. The nonce of a chunk is `file ID (16 bytes) || chunk index (8 bytes, little
+//! endian)` (see `nonce::make_nonce`), where the file ID is chosen at random for every file. A
+//! chunk that is moved to another position or into another file can therefore not be opened.
+//!
+//! The header records the chunk size and the total length of the message, and is authenticated
+//! with a keyed BLAKE2b-128 hash, keyed with a subkey derived with the context `SBoxFile`. The
+//! number of chunks and the length of the last chunk follow from the header, so a file that was
+//! truncated or extended is rejected.
+//!
+//! | Offset | Size | Content |
+//! |-|-|-|
+//! | 0 | 4 | Magic number `SBXC` |
+//! | 4 | 1 | Format version (currently 1) |
+//! | 5 | 1 | Algorithm ID (see `CipherType::id`) |
+//! | 6 | 4 | Chunk size (big endian) |
+//! | 10 | 8 | Total length of the message (big endian) |
+//! | 18 | 16 | File ID |
+//! | 34 | 16 | Header tag |
+//! | 50 | * | Chunks, each `tag || ciphertext` |
+//!
+//! `RandomAccessReader` implements `Read` and `Seek` and only opens the chunks that are read.
+use crate::blake2b::Blake2b;
+use crate::nonce::make_nonce;
+use crate::{kdf, CipherType, SecretBox};
+use rand::RngCore;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Magic number at the start of every chunked file
+pub const MAGIC: [u8; 4] = *b"SBXC";
+/// Current version of the chunked file format
+pub const VERSION: u8 = 1;
+/// Size of the header, including the header tag
+pub const HEADER_BYTES: usize = 4 + 1 + 1 + 4 + 8 + 16 + 16;
+
+/// Context used to derive the header key
+const CONTEXT: [u8; 8] = *b"SBoxFile";
+
+/// Errors that can occur while writing or reading a chunked file
+#[derive(Debug)]
+pub enum ChunkedError {
+    /// The file could not be read or written
+    Io(io::Error),
+    /// The data does not start with the magic number
+    BadMagic,
+    /// The file was created by an unsupported version of the format
+    UnsupportedVersion(u8),
+    /// The file uses an unknown algorithm
+    UnknownAlgorithm(u8),
+    /// The chunk size is 0
+    InvalidChunkSize,
+    /// The header could not be authenticated with the given key
+    Unauthentic,
+    /// The file is shorter or longer than the header says
+    Truncated,
+    /// The chunk with the given index could not be authenticated
+    CorruptChunk(u64),
+}
+
+impl fmt::Display for ChunkedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChunkedError::Io(e) => write!(f, "could not access chunked file: {}", e),
+            ChunkedError::BadMagic => write!(f, "data is not a chunked file"),
+            ChunkedError::UnsupportedVersion(v) => {
+                write!(f, "unsupported chunked file version {}", v)
+            }
+            ChunkedError::UnknownAlgorithm(a) => write!(f, "unknown algorithm ID {}", a),
+            ChunkedError::InvalidChunkSize => write!(f, "chunk size must not be 0"),
+            ChunkedError::Unauthentic => write!(f, "header could not be authenticated"),
+            ChunkedError::Truncated => write!(f, "file length does not match the header"),
+            ChunkedError::CorruptChunk(i) => write!(f, "chunk {} could not be authenticated", i),
+        }
+    }
+}
+
+impl std::error::Error for ChunkedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChunkedError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ChunkedError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            return ChunkedError::Truncated;
+        }
+        ChunkedError::Io(e)
+    }
+}
+
+/// Parsed header of a chunked file
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Header {
+    cipher: CipherType,
+    chunk_size: u32,
+    len: u64,
+    file_id: [u8; 16],
+}
+
+impl Header {
+    /// Serializes the header without the tag
+    fn fields(&self) -> [u8; HEADER_BYTES - 16] {
+        let mut out = [0u8; HEADER_BYTES - 16];
+        out[..4].copy_from_slice(&MAGIC);
+        out[4] = VERSION;
+        out[5] = self.cipher.id();
+        out[6..10].copy_from_slice(&self.chunk_size.to_be_bytes());
+        out[10..18].copy_from_slice(&self.len.to_be_bytes());
+        out[18..].copy_from_slice(&self.file_id);
+        out
+    }
+    /// Returns the number of chunks
+    fn chunks(&self) -> u64 {
+        self.len.div_ceil(u64::from(self.chunk_size))
+    }
+    /// Returns the total size of the file, or None if it overflows
+    fn file_len(&self) -> Option<u64> {
+        self.len
+            .checked_add(self.chunks().checked_mul(16)?)?
+            .checked_add(HEADER_BYTES as u64)
+    }
+}
+
+impl SecretBox {
+    /// Computes the tag of a header
+    fn header_tag(&self, fields: &[u8]) -> [u8; 16] {
+        let key = kdf::derive_from_key(&self.key, 0, CONTEXT);
+        let mut hash = Blake2b::new_keyed(16, &key);
+        hash.update(fields);
+        let mut tag = [0u8; 16];
+        tag.copy_from_slice(&hash.finalize());
+        tag
+    }
+
+    /// Encrypts `len` bytes read from `input` into a chunked file with a random file ID
+    ///
+    /// Returns an error if the chunk size is 0, or if `input` ends early or cannot be read
+    pub fn encrypt_chunked<R, W>(
+        &self,
+        mut input: R,
+        len: u64,
+        mut output: W,
+        chunk_size: u32,
+    ) -> Result<(), ChunkedError>
+    where
+        R: Read,
+        W: Write,
+    {
+        if chunk_size == 0 {
+            return Err(ChunkedError::InvalidChunkSize);
+        }
+        let mut file_id = [0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut file_id);
+        let header = Header {
+            cipher: self.cipher,
+            chunk_size,
+            len,
+            file_id,
+        };
+        let fields = header.fields();
+        output.write_all(&fields)?;
+        output.write_all(&self.header_tag(&fields))?;
+
+        let mut chunk = vec![0u8; len.min(u64::from(chunk_size)) as usize];
+        let mut remaining = len;
+        for index in 0..header.chunks() {
+            let n = remaining.min(u64::from(chunk_size)) as usize;
+            input.read_exact(&mut chunk[..n])?;
+            output.write_all(&self.seal(&chunk[..n], make_nonce(file_id, index)))?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Reader that decrypts a chunked file on demand
+#[derive(Debug)]
+pub struct RandomAccessReader<R> {
+    inner: R,
+    secret_box: SecretBox,
+    header: Header,
+    pos: u64,
+    /// Index and plaintext of the last chunk that was opened
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl<R> RandomAccessReader<R>
+where
+    R: Read + Seek,
+{
+    /// Reads and authenticates the header of a chunked file, and checks that the file has the
+    /// length given in the header
+    pub fn new(secret_box: &SecretBox, mut inner: R) -> Result<Self, ChunkedError> {
+        let mut data = [0u8; HEADER_BYTES];
+        inner.seek(SeekFrom::Start(0))?;
+        inner.read_exact(&mut data)?;
+        if data[..4] != MAGIC {
+            return Err(ChunkedError::BadMagic);
+        }
+        if data[4] != VERSION {
+            return Err(ChunkedError::UnsupportedVersion(data[4]));
+        }
+        let cipher = CipherType::from_id(data[5]).ok_or(ChunkedError::UnknownAlgorithm(data[5]))?;
+        let secret_box = SecretBox {
+            key: secret_box.key,
+            cipher,
+        };
+        let tag = secret_box.header_tag(&data[..HEADER_BYTES - 16]);
+        if !crate::constant_time_eq(&tag, &data[HEADER_BYTES - 16..]) {
+            return Err(ChunkedError::Unauthentic);
+        }
+        let mut file_id = [0u8; 16];
+        file_id.copy_from_slice(&data[18..34]);
+        let header = Header {
+            cipher,
+            chunk_size: u32::from_be_bytes([data[6], data[7], data[8], data[9]]),
+            len: u64::from_be_bytes([
+                data[10], data[11], data[12], data[13], data[14], data[15], data[16], data[17],
+            ]),
+            file_id,
+        };
+        if header.chunk_size == 0 {
+            return Err(ChunkedError::InvalidChunkSize);
+        }
+        if Some(inner.seek(SeekFrom::End(0))?) != header.file_len() {
+            return Err(ChunkedError::Truncated);
+        }
+        Ok(Self {
+            inner,
+            secret_box,
+            header,
+            pos: 0,
+            chunk: None,
+        })
+    }
+    /// Returns the length of the decrypted message
+    pub fn len(&self) -> u64 {
+        self.header.len
+    }
+    /// Returns whether the decrypted message is empty
+    pub fn is_empty(&self) -> bool {
+        self.header.len == 0
+    }
+    /// Returns the chunk size of the file
+    pub fn chunk_size(&self) -> u32 {
+        self.header.chunk_size
+    }
+    /// Returns the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+    /// Reads decrypted bytes starting at `pos` into `buf`, without moving the read position
+    ///
+    /// Returns the number of bytes read, which is 0 at or past the end of the message. Unlike
+    /// `Read::read`, a chunk that fails to authenticate is reported as `CorruptChunk`.
+    pub fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> Result<usize, ChunkedError> {
+        if pos >= self.header.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk_size = u64::from(self.header.chunk_size);
+        let offset = (pos % chunk_size) as usize;
+        let chunk = self.load(pos / chunk_size)?;
+        let n = buf.len().min(chunk.len() - offset);
+        buf[..n].copy_from_slice(&chunk[offset..offset + n]);
+        Ok(n)
+    }
+    /// Reads and opens the chunk with the given index, unless it is already loaded
+    fn load(&mut self, index: u64) -> Result<&[u8], ChunkedError> {
+        if self.chunk.as_ref().map(|c| c.0) != Some(index) {
+            let chunk_size = u64::from(self.header.chunk_size);
+            let start = index * chunk_size;
+            let len = (self.header.len - start).min(chunk_size) as usize;
+            let offset = HEADER_BYTES as u64 + index * (chunk_size + 16);
+            let mut sealed = vec![0u8; len + 16];
+            self.inner.seek(SeekFrom::Start(offset))?;
+            self.inner.read_exact(&mut sealed)?;
+            let nonce = make_nonce(self.header.file_id, index);
+            let plaintext = self
+                .secret_box
+                .unseal(&sealed, nonce)
+                .ok_or(ChunkedError::CorruptChunk(index))?;
+            self.chunk = Some((index, plaintext));
+        }
+        Ok(&self.chunk.as_ref().unwrap().1)
+    }
+}
+
+/// Errors other than I/O errors, such as `CorruptChunk`, are returned as an `io::Error` of kind
+/// `InvalidData` that wraps the `ChunkedError` (see `io::Error::get_ref`)
+impl<R> Read for RandomAccessReader<R>
+where
+    R: Read + Seek,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read_at(self.pos, buf).map_err(|e| match e {
+            ChunkedError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for RandomAccessReader<R>
+where
+    R: Read + Seek,
+{
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => checked_offset(self.header.len, d),
+            SeekFrom::Current(d) => checked_offset(self.pos, d),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Adds a signed offset to a position
+fn checked_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset < 0 {
+        base.checked_sub(offset.unsigned_abs())
+    } else {
+        base.checked_add(offset as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn encrypt(s: &SecretBox, message: &[u8], chunk_size: u32) -> Vec<u8> {
+        let mut out = Vec::new();
+        s.encrypt_chunked(message, message.len() as u64, &mut out, chunk_size)
+            .unwrap();
+        out
+    }
+
+    fn open(s: &SecretBox, file: Vec<u8>) -> Result<Vec<u8>, ChunkedError> {
+        let mut reader = RandomAccessReader::new(s, Cursor::new(file))?;
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn random_access() {
+        let s = SecretBox::new([6u8; 32], CipherType::Chacha20).unwrap();
+        let message: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        for &len in [0, 1, 16, 17, 1000].iter() {
+            let file = encrypt(&s, &message[..len], 16);
+            let chunks = len.div_ceil(16);
+            assert_eq!(file.len(), HEADER_BYTES + len + 16 * chunks);
+            assert_eq!(open(&s, file).unwrap(), message[..len].to_vec());
+        }
+
+        let file = encrypt(&s, &message, 64);
+        let mut reader = RandomAccessReader::new(&s, Cursor::new(file)).unwrap();
+        assert_eq!((reader.len(), reader.chunk_size()), (1000, 64));
+        let mut buf = [0u8; 100];
+        assert_eq!(reader.seek(SeekFrom::Start(500)).unwrap(), 500);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf[..], &message[500..600]);
+        assert_eq!(reader.seek(SeekFrom::End(-10)).unwrap(), 990);
+        assert_eq!(reader.read(&mut buf).unwrap(), 10);
+        assert_eq!(&buf[..10], &message[990..]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(reader.seek(SeekFrom::Current(-995)).unwrap(), 5);
+        reader.read_exact(&mut buf[..3]).unwrap();
+        assert_eq!(&buf[..3], &message[5..8]);
+        assert!(reader.seek(SeekFrom::Current(-9)).is_err());
+    }
+
+    #[test]
+    fn tampering() {
+        let s = SecretBox::new([6u8; 32], CipherType::Salsa20).unwrap();
+        let message = [0x5au8; 100];
+        let file = encrypt(&s, &message, 32);
+        let chunk = 32 + 16;
+
+        // Swapping the first two chunks
+        let mut reordered = file.clone();
+        reordered[HEADER_BYTES..HEADER_BYTES + 2 * chunk].rotate_left(chunk);
+        let mut reader = RandomAccessReader::new(&s, Cursor::new(reordered.clone())).unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(reader.read_at(70, &mut buf).unwrap(), 8);
+        assert_eq!(buf, [0x5au8; 8]);
+        match reader.read_at(40, &mut buf) {
+            Err(ChunkedError::CorruptChunk(1)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        reader.seek(SeekFrom::Start(40)).unwrap();
+        let err = reader.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match err.get_ref().and_then(|e| e.downcast_ref::<ChunkedError>()) {
+            Some(ChunkedError::CorruptChunk(1)) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+        match open(&s, reordered) {
+            Err(ChunkedError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Dropping the last chunk, or appending data
+        for len in [file.len() - 1, file.len() - 20, HEADER_BYTES, 10].iter() {
+            match open(&s, file[..*len].to_vec()) {
+                Err(ChunkedError::Truncated) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        let mut extended = file.clone();
+        extended.push(0);
+        match open(&s, extended) {
+            Err(ChunkedError::Truncated) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // Changing the header, or opening with another key
+        for &i in [6, 17, 20, 40].iter() {
+            let mut forged = file.clone();
+            forged[i] ^= 1;
+            match open(&s, forged) {
+                Err(ChunkedError::Unauthentic) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        let other = SecretBox::new([7u8; 32], CipherType::Salsa20).unwrap();
+        match open(&other, file.clone()) {
+            Err(ChunkedError::Unauthentic) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let mut bad = file;
+        bad[4] = 2;
+        match open(&s, bad) {
+            Err(ChunkedError::UnsupportedVersion(2)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut out = Vec::new();
+        match s.encrypt_chunked(&message[..], 200, &mut out, 32) {
+            Err(ChunkedError::Truncated) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match s.encrypt_chunked(&message[..], 100, &mut out, 0) {
+            Err(ChunkedError::InvalidChunkSize) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
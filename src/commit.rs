@@ -0,0 +1,102 @@
+//! Key-committing boxes
+//!
+//! Poly1305 is not collision resistant in its key, so it is possible to craft a box that opens
+//! successfully under two different keys, with different plaintexts. Protocols that try several
+//! keys (multiple recipients, password guesses) can be attacked this way ("partitioning oracle"
+//! attacks).
+//!
+//! A committing box starts with a commitment to the key: a BLAKE2b-256 hash of the key and the
+//! nonce, personalized for this purpose. Before a box is decrypted, the commitment is recomputed
+//! and compared in constant time, so a box can only be opened with the key it was sealed with.
+//! The key is hashed directly instead of the HSalsa20 subkey, as HSalsa20 has no feed-forward
+//! and is not meant to be collision resistant in its key.
+//!
+//! The format of a committing box is `commitment (32 bytes) || tag || ciphertext`.
+use crate::blake2b::Blake2b;
+use crate::SecretBox;
+
+/// Size of the key commitment
+pub const COMMITMENT_BYTES: usize = 32;
+
+/// Personalization of the commitment hash
+const PERSONAL: [u8; 16] = *b"SBoxCommitment\0\0";
+
+impl SecretBox {
+    /// Computes the commitment to the key for the given nonce
+    pub fn commitment(&self, nonce: [u8; 24]) -> [u8; COMMITMENT_BYTES] {
+        let mut hash = Blake2b::with_params(COMMITMENT_BYTES, &[], [0u8; 16], PERSONAL);
+        hash.update(&self.key);
+        hash.update(&nonce);
+        let mut commitment = [0u8; COMMITMENT_BYTES];
+        commitment.copy_from_slice(&hash.finalize());
+        commitment
+    }
+
+    /// Seals a message and prepends the commitment to the key
+    pub fn seal_committing(&self, message: &[u8], nonce: [u8; 24]) -> Vec<u8> {
+        let mut out = vec![0u8; COMMITMENT_BYTES + 16 + message.len()];
+        out[..COMMITMENT_BYTES].copy_from_slice(&self.commitment(nonce));
+        self.seal_into(message, nonce, &mut out[COMMITMENT_BYTES..]);
+        out
+    }
+
+    /// Opens a box created by `seal_committing`. The commitment is checked before the box is
+    /// authenticated and decrypted.
+    ///
+    /// Returns None if the box was sealed with a different key or is not authentic
+    pub fn open_committing(&self, data: &[u8], nonce: [u8; 24]) -> Option<Vec<u8>> {
+        if data.len() < COMMITMENT_BYTES + 16 {
+            return None;
+        }
+        if !crate::constant_time_eq(&self.commitment(nonce), &data[..COMMITMENT_BYTES]) {
+            return None;
+        }
+        self.unseal(&data[COMMITMENT_BYTES..], nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CipherType;
+
+    #[test]
+    fn committing() {
+        let s = SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap();
+        let sealed = s.seal_committing(b"transfer 10 EUR", [2u8; 24]);
+        assert_eq!(&sealed[..COMMITMENT_BYTES], &s.commitment([2u8; 24])[..]);
+        assert_eq!(
+            &sealed[COMMITMENT_BYTES..],
+            &s.seal(b"transfer 10 EUR", [2u8; 24])[..]
+        );
+        assert_eq!(
+            s.open_committing(&sealed, [2u8; 24]).unwrap(),
+            b"transfer 10 EUR".to_vec()
+        );
+        assert_eq!(s.open_committing(&sealed, [3u8; 24]), None);
+        assert_eq!(s.open_committing(&sealed[..47], [2u8; 24]), None);
+
+        // A box for another key does not open, even with a valid inner box
+        let other = SecretBox::new([9u8; 32], CipherType::Salsa20).unwrap();
+        let mut mixed = sealed[..COMMITMENT_BYTES].to_vec();
+        mixed.extend_from_slice(&other.seal(b"transfer 99 EUR", [2u8; 24]));
+        assert_eq!(other.open_committing(&mixed, [2u8; 24]), None);
+        assert_eq!(s.open_committing(&mixed, [2u8; 24]), None);
+
+        let mut forged = sealed;
+        forged[0] ^= 1;
+        assert_eq!(s.open_committing(&forged, [2u8; 24]), None);
+    }
+
+    #[test]
+    fn vector() {
+        let s = SecretBox::new([0x42u8; 32], CipherType::Salsa20).unwrap();
+        let expected = "d2b89be7b8deb8843d0508c5322031fc646e960af733f781440dce7958c61405";
+        let commitment: String = s
+            .commitment([0x24u8; 24])
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(commitment, expected);
+    }
+}
@@ -0,0 +1,281 @@
+//! Public-key authenticated encryption compatible with NaCl's `crypto_box`
+//!
+//! A box is created by doing an X25519 key exchange between the sender's secret key and the
+//! recipient's public key. The shared secret is hashed with HSalsa20 and then used as the key of
+//! an XSalsa20-Poly1305 `SecretBox`. The recipient can open the box with their secret key and the
+//! sender's public key.
+//!
+//! Anonymous boxes (`crypto_box_seal`) only need the recipient's public key. They are sealed with
+//! a fresh ephemeral key pair, whose public key is prepended to the box.
+use crate::blake2b::Blake2b;
+use crate::kdf::{hsalsa20, SIGMA};
+use crate::{constant_time_eq, CipherType, SecretBox};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// X25519 public key
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    /// Reads a public key from a slice
+    ///
+    /// Returns None if the slice is not 32 bytes long
+    pub fn from_slice(k: &[u8]) -> Option<Self> {
+        if k.len() != 32 {
+            return None;
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(k);
+        Some(Self(key))
+    }
+    /// Returns the raw bytes of the public key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<[u8; 32]> for PublicKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+/// X25519 secret key
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// Reads a secret key from a slice
+    ///
+    /// Returns None if the slice is not 32 bytes long
+    pub fn from_slice(k: &[u8]) -> Option<Self> {
+        if k.len() != 32 {
+            return None;
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(k);
+        Some(Self(key))
+    }
+    /// Returns the raw bytes of the secret key
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+    /// Calculates the public key belonging to this secret key
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519(self.0, X25519_BASEPOINT_BYTES))
+    }
+}
+
+impl From<[u8; 32]> for SecretKey {
+    fn from(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+}
+
+/// Generates a new random key pair
+pub fn keypair<R>(rng: &mut R) -> (PublicKey, SecretKey)
+where
+    R: rand::Rng + rand::CryptoRng,
+{
+    let mut buf = [0u8; 32];
+    rng.fill_bytes(&mut buf);
+    let sk = SecretKey(buf);
+    (sk.public_key(), sk)
+}
+
+/// Precomputes the shared key between a public key and a secret key (`crypto_box_beforenm`)
+///
+/// The returned `SecretBox` can be used to seal and open any number of boxes between the two
+/// parties, without repeating the key exchange.
+///
+/// Returns None if `pk` is a low-order point, which makes the shared secret all zeros
+pub fn beforenm(pk: &PublicKey, sk: &SecretKey) -> Option<SecretBox> {
+    let shared = x25519(sk.0, pk.0);
+    if constant_time_eq(&shared, &[0u8; 32]) {
+        return None;
+    }
+    Some(SecretBox {
+        key: hsalsa20([0u8; 16], shared, SIGMA),
+        cipher: CipherType::Salsa20,
+    })
+}
+
+/// Encrypts and authenticates a message from the owner of `sk` to the owner of `pk`. The nonce
+/// must be unique for every message between the two parties.
+///
+/// Returns None if `pk` is a low-order point
+pub fn seal(message: &[u8], nonce: [u8; 24], pk: &PublicKey, sk: &SecretKey) -> Option<Vec<u8>> {
+    Some(beforenm(pk, sk)?.seal(message, nonce))
+}
+
+/// Authenticates and decrypts a box sent by the owner of `pk` to the owner of `sk`
+pub fn open(data: &[u8], nonce: [u8; 24], pk: &PublicKey, sk: &SecretKey) -> Option<Vec<u8>> {
+    beforenm(pk, sk)?.unseal(data, nonce)
+}
+
+/// Size of the overhead added by `seal_anonymous`
+pub const SEAL_BYTES: usize = 32 + 16;
+
+fn anonymous_nonce(ephemeral_pk: &PublicKey, recipient_pk: &PublicKey) -> [u8; 24] {
+    let mut state = Blake2b::new(24);
+    state.update(&ephemeral_pk.0);
+    state.update(&recipient_pk.0);
+    let mut nonce = [0u8; 24];
+    nonce.copy_from_slice(&state.finalize());
+    nonce
+}
+
+fn seal_anonymous_with(
+    message: &[u8],
+    recipient_pk: &PublicKey,
+    ephemeral_sk: &SecretKey,
+) -> Option<Vec<u8>> {
+    let ephemeral_pk = ephemeral_sk.public_key();
+    let nonce = anonymous_nonce(&ephemeral_pk, recipient_pk);
+    let mut out = Vec::with_capacity(message.len() + SEAL_BYTES);
+    out.extend_from_slice(&ephemeral_pk.0);
+    out.extend_from_slice(&seal(message, nonce, recipient_pk, ephemeral_sk)?);
+    Some(out)
+}
+
+/// Encrypts a message for the owner of `recipient_pk` without identifying the sender
+/// (`crypto_box_seal`).
+///
+/// The recipient can verify that the box was not tampered with, but not who sent it. Returns
+/// None if `recipient_pk` is a low-order point.
+pub fn seal_anonymous(recipient_pk: &PublicKey, message: &[u8]) -> Option<Vec<u8>> {
+    let (_, ephemeral_sk) = keypair(&mut rand::rngs::OsRng);
+    seal_anonymous_with(message, recipient_pk, &ephemeral_sk)
+}
+
+/// Opens a box created by `seal_anonymous` using the recipient's key pair
+/// (`crypto_box_seal_open`).
+pub fn open_anonymous(keypair: &(PublicKey, SecretKey), data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < SEAL_BYTES {
+        return None;
+    }
+    let ephemeral_pk = PublicKey::from_slice(&data[..32])?;
+    let nonce = anonymous_nonce(&ephemeral_pk, &keypair.0);
+    open(&data[32..], nonce, &ephemeral_pk, &keypair.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    const ALICE_SK: [u8; 32] = [
+        0x77, 0x07, 0x6d, 0x0a, 0x73, 0x18, 0xa5, 0x7d, 0x3c, 0x16, 0xc1, 0x72, 0x51, 0xb2, 0x66,
+        0x45, 0xdf, 0x4c, 0x2f, 0x87, 0xeb, 0xc0, 0x99, 0x2a, 0xb1, 0x77, 0xfb, 0xa5, 0x1d, 0xb9,
+        0x2c, 0x2a,
+    ];
+    const ALICE_PK: [u8; 32] = [
+        0x85, 0x20, 0xf0, 0x09, 0x89, 0x30, 0xa7, 0x54, 0x74, 0x8b, 0x7d, 0xdc, 0xb4, 0x3e, 0xf7,
+        0x5a, 0x0d, 0xbf, 0x3a, 0x0d, 0x26, 0x38, 0x1a, 0xf4, 0xeb, 0xa4, 0xa9, 0x8e, 0xaa, 0x9b,
+        0x4e, 0x6a,
+    ];
+    const BOB_SK: [u8; 32] = [
+        0x5d, 0xab, 0x08, 0x7e, 0x62, 0x4a, 0x8a, 0x4b, 0x79, 0xe1, 0x7f, 0x8b, 0x83, 0x80, 0x0e,
+        0xe6, 0x6f, 0x3b, 0xb1, 0x29, 0x26, 0x18, 0xb6, 0xfd, 0x1c, 0x2f, 0x8b, 0x27, 0xff, 0x88,
+        0xe0, 0xeb,
+    ];
+    const BOB_PK: [u8; 32] = [
+        0xde, 0x9e, 0xdb, 0x7d, 0x7b, 0x7d, 0xc1, 0xb4, 0xd3, 0x5b, 0x61, 0xc2, 0xec, 0xe4, 0x35,
+        0x37, 0x3f, 0x83, 0x43, 0xc8, 0x5b, 0x78, 0x67, 0x4d, 0xad, 0xfc, 0x7e, 0x14, 0x6f, 0x88,
+        0x2b, 0x4f,
+    ];
+
+    #[test]
+    fn public_keys() {
+        assert_eq!(
+            SecretKey::from(ALICE_SK).public_key(),
+            PublicKey::from(ALICE_PK)
+        );
+        assert_eq!(
+            SecretKey::from(BOB_SK).public_key(),
+            PublicKey::from(BOB_PK)
+        );
+    }
+
+    #[test]
+    fn nacl_vector() {
+        let firstkey = [
+            0x1b, 0x27, 0x55, 0x64, 0x73, 0xe9, 0x85, 0xd4, 0x62, 0xcd, 0x51, 0x19, 0x7a, 0x9a,
+            0x46, 0xc7, 0x60, 0x09, 0x54, 0x9e, 0xac, 0x64, 0x74, 0xf2, 0x06, 0xc4, 0xee, 0x08,
+            0x44, 0xf6, 0x83, 0x89,
+        ];
+        let alice = beforenm(&BOB_PK.into(), &ALICE_SK.into()).unwrap();
+        let bob = beforenm(&ALICE_PK.into(), &BOB_SK.into()).unwrap();
+        assert_eq!(
+            alice,
+            SecretBox::new(firstkey, CipherType::Salsa20).unwrap()
+        );
+        assert_eq!(alice, bob);
+    }
+
+    #[test]
+    fn seal_open() {
+        let mut rng = rand::rngs::OsRng;
+        let (alice_pk, alice_sk) = keypair(&mut rng);
+        let (bob_pk, bob_sk) = keypair(&mut rng);
+        let nonce = [7u8; 24];
+        let sealed = seal(b"hello bob", nonce, &bob_pk, &alice_sk).unwrap();
+        assert_eq!(
+            open(&sealed, nonce, &alice_pk, &bob_sk).unwrap(),
+            b"hello bob".to_vec()
+        );
+        let (eve_pk, _) = keypair(&mut rng);
+        assert_eq!(open(&sealed, nonce, &eve_pk, &bob_sk), None);
+    }
+
+    #[test]
+    fn anonymous_nonce_derivation() {
+        // BLAKE2b-192(alice_pk || bob_pk)
+        let nonce = [
+            0xbd, 0xe6, 0x8c, 0x30, 0x07, 0xdd, 0x6c, 0x60, 0x38, 0x61, 0x86, 0x66, 0xc8, 0x83,
+            0x0b, 0x02, 0x3d, 0x3c, 0x0a, 0x8e, 0x98, 0x8a, 0x8d, 0x38,
+        ];
+        let sealed = seal_anonymous_with(b"report", &BOB_PK.into(), &ALICE_SK.into()).unwrap();
+        assert_eq!(&sealed[..32], &ALICE_PK[..]);
+        assert_eq!(
+            &sealed[32..],
+            &seal(b"report", nonce, &BOB_PK.into(), &ALICE_SK.into()).unwrap()[..]
+        );
+        let bob = (PublicKey::from(BOB_PK), SecretKey::from(BOB_SK));
+        assert_eq!(open_anonymous(&bob, &sealed).unwrap(), b"report".to_vec());
+    }
+
+    #[test]
+    fn anonymous_seal_open() {
+        let recipient = keypair(&mut rand::rngs::OsRng);
+        let sealed = seal_anonymous(&recipient.0, b"hello collector").unwrap();
+        assert_eq!(sealed.len(), 15 + SEAL_BYTES);
+        assert_eq!(
+            open_anonymous(&recipient, &sealed).unwrap(),
+            b"hello collector".to_vec()
+        );
+        let other = keypair(&mut rand::rngs::OsRng);
+        assert_eq!(open_anonymous(&other, &sealed), None);
+        assert_eq!(open_anonymous(&recipient, &sealed[..SEAL_BYTES - 1]), None);
+    }
+
+    #[test]
+    fn low_order_points() {
+        // The identity and a point of order 8 both give an all-zero shared secret
+        const ORDER_8: [u8; 32] = [
+            0xe0, 0xeb, 0x7a, 0x7c, 0x3b, 0x41, 0xb8, 0xae, 0x16, 0x56, 0xe3, 0xfa, 0xf1, 0x9f,
+            0xc4, 0x6a, 0xda, 0x09, 0x8d, 0xeb, 0x9c, 0x32, 0xb1, 0xfd, 0x86, 0x62, 0x05, 0x16,
+            0x5f, 0x49, 0xb8, 0x00,
+        ];
+        let sk = SecretKey::from(ALICE_SK);
+        let bob = (PublicKey::from(BOB_PK), SecretKey::from(BOB_SK));
+        for &pk in [[0u8; 32], ORDER_8].iter() {
+            let pk = PublicKey::from(pk);
+            assert_eq!(beforenm(&pk, &sk), None);
+            assert_eq!(seal(b"hello", [7u8; 24], &pk, &sk), None);
+            assert_eq!(open(&[0u8; 16], [7u8; 24], &pk, &sk), None);
+            assert_eq!(seal_anonymous(&pk, b"hello"), None);
+            let mut sealed = pk.as_bytes().to_vec();
+            sealed.extend_from_slice(&[0u8; 16]);
+            assert_eq!(open_anonymous(&bob, &sealed), None);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Self-describing envelope format for sealed boxes
+//!
+//! An envelope records everything except the key that is needed to open a box:
+//!
+//! | Offset | Size | Content |
+//! |-|-|-|
+//! | 0 | 4 | Magic number `SBOX` |
+//! | 4 | 1 | Format version (currently 1) |
+//! | 5 | 1 | Algorithm ID (see `CipherType::id`) |
+//! | 6 | 24 | Nonce |
+//! | 30 | 16 | Poly1305 tag |
+//! | 46 | * | Ciphertext |
+use crate::{CipherType, SecretBox};
+use std::fmt;
+
+/// Magic number at the start of every envelope
+pub const MAGIC: [u8; 4] = *b"SBOX";
+/// Current version of the envelope format
+pub const VERSION: u8 = 1;
+/// Size of the envelope header (magic, version, algorithm ID and nonce)
+pub const HEADER_BYTES: usize = 4 + 1 + 1 + 24;
+
+/// Errors that can occur while parsing or opening an envelope
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EnvelopeError {
+    /// The data is too short to be an envelope
+    Truncated,
+    /// The data does not start with the magic number
+    BadMagic,
+    /// The envelope was created by an unsupported version of the format
+    UnsupportedVersion(u8),
+    /// The envelope uses an unknown algorithm
+    UnknownAlgorithm(u8),
+    /// The box could not be authenticated with the given key
+    Unauthentic,
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnvelopeError::Truncated => write!(f, "envelope is truncated"),
+            EnvelopeError::BadMagic => write!(f, "data is not an envelope"),
+            EnvelopeError::UnsupportedVersion(v) => {
+                write!(f, "unsupported envelope version {}", v)
+            }
+            EnvelopeError::UnknownAlgorithm(a) => write!(f, "unknown algorithm ID {}", a),
+            EnvelopeError::Unauthentic => write!(f, "box could not be authenticated"),
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// Parsed envelope, borrowing the sealed box from the input
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Envelope<'a> {
+    /// Algorithm the box was sealed with
+    pub cipher: CipherType,
+    /// Nonce the box was sealed with
+    pub nonce: [u8; 24],
+    /// Tag and ciphertext, as returned by `SecretBox::seal`
+    pub sealed: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    /// Parses an envelope without authenticating it
+    pub fn parse(data: &'a [u8]) -> Result<Self, EnvelopeError> {
+        if data.len() < 6 {
+            return Err(EnvelopeError::Truncated);
+        }
+        if data[..4] != MAGIC {
+            return Err(EnvelopeError::BadMagic);
+        }
+        if data[4] != VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(data[4]));
+        }
+        let cipher =
+            CipherType::from_id(data[5]).ok_or(EnvelopeError::UnknownAlgorithm(data[5]))?;
+        if data.len() < HEADER_BYTES + 16 {
+            return Err(EnvelopeError::Truncated);
+        }
+        let mut nonce = [0u8; 24];
+        nonce.copy_from_slice(&data[6..HEADER_BYTES]);
+        Ok(Self {
+            cipher,
+            nonce,
+            sealed: &data[HEADER_BYTES..],
+        })
+    }
+    /// Serializes the envelope
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_BYTES + self.sealed.len());
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.push(self.cipher.id());
+        out.extend_from_slice(&self.nonce);
+        out.extend_from_slice(self.sealed);
+        out
+    }
+}
+
+impl SecretBox {
+    /// Seals a message with a random nonce and wraps it in an envelope
+    pub fn seal_envelope(&self, message: &[u8]) -> Vec<u8> {
+        use rand::RngCore;
+        let mut nonce = [0u8; 24];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let sealed = self.seal(message, nonce);
+        Envelope {
+            cipher: self.cipher,
+            nonce,
+            sealed: &sealed,
+        }
+        .to_bytes()
+    }
+    /// Opens an envelope with the cipher named by its algorithm ID
+    pub fn open_envelope(&self, data: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+        open_with_key(self.key, data)
+    }
+}
+
+/// Opens an envelope with a raw key, for callers that do not have a `SecretBox` yet
+pub(crate) fn open_with_key(key: [u8; 32], data: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    let envelope = Envelope::parse(data)?;
+    let secret_box = SecretBox {
+        key,
+        cipher: envelope.cipher,
+    };
+    secret_box
+        .unseal(envelope.sealed, envelope.nonce)
+        .ok_or(EnvelopeError::Unauthentic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn dispatch() {
+        let salsa20 = SecretBox::new([4u8; 32], CipherType::Salsa20).unwrap();
+        let chacha20 = SecretBox::new([4u8; 32], CipherType::Chacha20).unwrap();
+        let sealed = chacha20.seal_envelope(b"hello");
+        let envelope = Envelope::parse(&sealed).unwrap();
+        assert_eq!(envelope.cipher, CipherType::Chacha20);
+        assert_eq!(envelope.sealed.len(), 5 + 16);
+        assert_eq!(envelope.to_bytes(), sealed);
+        assert_eq!(salsa20.open_envelope(&sealed).unwrap(), b"hello".to_vec());
+        let other = SecretBox::new([5u8; 32], CipherType::Chacha20).unwrap();
+        assert_eq!(
+            other.open_envelope(&sealed),
+            Err(EnvelopeError::Unauthentic)
+        );
+    }
+    #[test]
+    fn errors() {
+        let s = SecretBox::new([4u8; 32], CipherType::Salsa20).unwrap();
+        let sealed = s.seal_envelope(b"hello");
+        assert_eq!(s.open_envelope(&sealed[..5]), Err(EnvelopeError::Truncated));
+        assert_eq!(
+            s.open_envelope(&sealed[..HEADER_BYTES + 15]),
+            Err(EnvelopeError::Truncated)
+        );
+        let mut bad = sealed.clone();
+        bad[0] = b'X';
+        assert_eq!(s.open_envelope(&bad), Err(EnvelopeError::BadMagic));
+        let mut bad = sealed.clone();
+        bad[4] = 2;
+        assert_eq!(
+            s.open_envelope(&bad),
+            Err(EnvelopeError::UnsupportedVersion(2))
+        );
+        let mut bad = sealed.clone();
+        bad[5] = 0xff;
+        assert_eq!(
+            s.open_envelope(&bad),
+            Err(EnvelopeError::UnknownAlgorithm(0xff))
+        );
+        let mut bad = sealed;
+        bad[HEADER_BYTES] ^= 1;
+        assert_eq!(s.open_envelope(&bad), Err(EnvelopeError::Unauthentic));
+    }
+}
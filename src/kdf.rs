@@ -1,11 +1,30 @@
+//! Key derivation
+//!
+//! HSalsa20 hashes a 16-byte input and a 32-byte key into a 32-byte output. It is used to extend
+//! the nonce of Salsa20 and Chacha20 to 24 bytes (XSalsa20/XChacha20), and to turn the raw output
+//! of an X25519 key exchange into a uniformly random key (NaCl's `crypto_box_beforenm`).
+//!
+//! `derive_from_key` derives any number of subkeys from a single master key, like libsodium's
+//! `crypto_kdf_derive_from_key`.
+use crate::blake2b::Blake2b;
 use crate::salsa20::implementation::salsa20_rounds;
 
+/// SIGMA constant used by NaCl for key expansion and derivation
+/// Reads "expand 32-byte k"
 pub const SIGMA: [u8; 16] = [
     0x65, 0x78, 0x70, 0x61, 0x6e, 0x64, 0x20, 0x33, 0x32, 0x2d, 0x62, 0x79, 0x74, 0x65, 0x20, 0x6B,
 ];
 
+/// Computes HSalsa20 of a 16-byte input under a 32-byte key and a 16-byte constant.
+///
+/// Unless you are implementing your own construction, `c` should be `SIGMA`.
 pub fn hsalsa20(data: [u8; 16], k: [u8; 32], c: [u8; 16]) -> [u8; 32] {
-    let data = [
+    hsalsa20_output(salsa20_rounds(hsalsa20_input(data, k, c)))
+}
+
+/// Builds the Salsa20 input of HSalsa20
+pub(crate) fn hsalsa20_input(data: [u8; 16], k: [u8; 32], c: [u8; 16]) -> [u32; 16] {
+    [
         u32::from_le_bytes([c[0], c[1], c[2], c[3]]),
         u32::from_le_bytes([k[0], k[1], k[2], k[3]]),
         u32::from_le_bytes([k[4], k[5], k[6], k[7]]),
@@ -22,8 +41,11 @@ pub fn hsalsa20(data: [u8; 16], k: [u8; 32], c: [u8; 16]) -> [u8; 32] {
         u32::from_le_bytes([k[24], k[25], k[26], k[27]]),
         u32::from_le_bytes([k[28], k[29], k[30], k[31]]),
         u32::from_le_bytes([c[12], c[13], c[14], c[15]]),
-    ];
-    let data = salsa20_rounds(data);
+    ]
+}
+
+/// Extracts the HSalsa20 output from the state after the Salsa20 rounds
+pub(crate) fn hsalsa20_output(data: [u32; 16]) -> [u8; 32] {
     let x0 = data[0].to_le_bytes();
     let x5 = data[5].to_le_bytes();
     let x6 = data[6].to_le_bytes();
@@ -39,6 +61,11 @@ pub fn hsalsa20(data: [u8; 16], k: [u8; 32], c: [u8; 16]) -> [u8; 32] {
     ]
 }
 
+/// Splits a 24-byte XSalsa20/XChacha20 nonce and derives the subkey for it.
+///
+/// The first 16 bytes of the nonce are hashed together with the key using HSalsa20. The returned
+/// subkey is then used together with the remaining 8 bytes of the nonce, which are returned as
+/// the second element of the tuple.
 pub fn generate_subkey(nonce: [u8; 24], key: [u8; 32]) -> ([u8; 32], [u8; 8]) {
     let hnonce = [
         nonce[0], nonce[1], nonce[2], nonce[3], nonce[4], nonce[5], nonce[6], nonce[7], nonce[8],
@@ -53,6 +80,20 @@ pub fn generate_subkey(nonce: [u8; 24], key: [u8; 32]) -> ([u8; 32], [u8; 8]) {
     )
 }
 
+/// Derives the subkey with the given ID from a master key (`crypto_kdf_derive_from_key`)
+///
+/// The context is an arbitrary 8-byte string describing what the subkeys are used for, for
+/// example `b"Invoices"`. Different contexts yield unrelated subkeys for the same ID.
+pub fn derive_from_key(master: &[u8; 32], subkey_id: u64, context: [u8; 8]) -> [u8; 32] {
+    let mut salt = [0u8; 16];
+    salt[..8].copy_from_slice(&subkey_id.to_le_bytes());
+    let mut personal = [0u8; 16];
+    personal[..8].copy_from_slice(&context);
+    let mut subkey = [0u8; 32];
+    subkey.copy_from_slice(&Blake2b::with_params(32, master, salt, personal).finalize());
+    subkey
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -71,4 +112,60 @@ mod tests {
         ];
         assert_eq!(hsalsa20(input, shared, SIGMA), expected);
     }
+    #[test]
+    fn subkey() {
+        let key = [
+            0x1b, 0x27, 0x55, 0x64, 0x73, 0xe9, 0x85, 0xd4, 0x62, 0xcd, 0x51, 0x19, 0x7a, 0x9a,
+            0x46, 0xc7, 0x60, 0x09, 0x54, 0x9e, 0xac, 0x64, 0x74, 0xf2, 0x06, 0xc4, 0xee, 0x08,
+            0x44, 0xf6, 0x83, 0x89,
+        ];
+        let nonce = [
+            0x69, 0x69, 0x6e, 0xe9, 0x55, 0xb6, 0x2b, 0x73, 0xcd, 0x62, 0xbd, 0xa8, 0x75, 0xfc,
+            0x73, 0xd6, 0x82, 0x19, 0xe0, 0x03, 0x6b, 0x7a, 0x0b, 0x37,
+        ];
+        let expected = [
+            0xdc, 0x90, 0x8d, 0xda, 0x0b, 0x93, 0x44, 0xa9, 0x53, 0x62, 0x9b, 0x73, 0x38, 0x20,
+            0x77, 0x88, 0x80, 0xf3, 0xce, 0xb4, 0x21, 0xbb, 0x61, 0xb9, 0x1c, 0xbd, 0x4c, 0x3e,
+            0x66, 0x25, 0x6c, 0xe4,
+        ];
+        assert_eq!(
+            generate_subkey(nonce, key),
+            (expected, [0x82, 0x19, 0xe0, 0x03, 0x6b, 0x7a, 0x0b, 0x37])
+        );
+    }
+    #[test]
+    fn kdf() {
+        let mut master = [0u8; 32];
+        for (i, b) in master.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        assert_eq!(
+            derive_from_key(&master, 0, *b"KDF test"),
+            [
+                0xc1, 0x3f, 0xcc, 0x2e, 0x6c, 0xd0, 0xcd, 0x0f, 0x82, 0xd9, 0x3b, 0x16, 0x3a, 0x56,
+                0x96, 0xc5, 0x10, 0x53, 0x78, 0xf8, 0xc6, 0x29, 0xd3, 0x6b, 0xaf, 0x3a, 0xe0, 0x23,
+                0x9d, 0xe9, 0xc2, 0x80
+            ]
+        );
+        assert_eq!(
+            derive_from_key(&master, 1, *b"KDF test"),
+            [
+                0x13, 0xfe, 0xa5, 0x2b, 0xb8, 0xcb, 0xa0, 0x63, 0xf3, 0xed, 0x93, 0xde, 0x27, 0xed,
+                0x07, 0xe0, 0x6d, 0x8c, 0x63, 0x67, 0x47, 0x4e, 0x6a, 0xe4, 0xc9, 0x28, 0x29, 0x13,
+                0xac, 0x3c, 0x3a, 0x03
+            ]
+        );
+        assert_eq!(
+            derive_from_key(&master, 1 << 63, *b"KDF test"),
+            [
+                0x9e, 0x7b, 0x36, 0x97, 0x88, 0x32, 0x69, 0x0e, 0xfc, 0xbf, 0x1d, 0xd2, 0xbe, 0x42,
+                0x3e, 0x80, 0x1a, 0x47, 0x94, 0xaf, 0x30, 0xc4, 0x5e, 0x32, 0xfa, 0xaa, 0x70, 0x9c,
+                0xa6, 0xc2, 0x0d, 0x49
+            ]
+        );
+        assert_ne!(
+            derive_from_key(&master, 0, *b"KDF tesu"),
+            derive_from_key(&master, 0, *b"KDF test")
+        );
+    }
 }
@@ -0,0 +1,155 @@
+//! Key rotation using a set of keys identified by short key IDs
+//!
+//! A `Keyring` holds one primary key, which is used for sealing new messages, and any number of
+//! retired keys, which are only used for opening old messages. Every sealed message starts with
+//! the ID of the key it was sealed with, so that the matching key can be selected when opening it.
+//!
+//! The format of a sealed message is `key_id (4 bytes, big endian) || nonce || tag || ciphertext`.
+use crate::SecretBox;
+use std::collections::BTreeMap;
+
+/// Identifier of a key in a keyring
+pub type KeyId = u32;
+
+/// Size of the key ID prefix
+pub const KEY_ID_BYTES: usize = 4;
+
+/// Set of keys with one primary key
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Keyring {
+    primary: KeyId,
+    keys: BTreeMap<KeyId, SecretBox>,
+}
+
+impl Keyring {
+    /// Creates a new keyring with a single primary key
+    pub fn new(id: KeyId, primary: SecretBox) -> Self {
+        let mut keys = BTreeMap::new();
+        keys.insert(id, primary);
+        Self { primary: id, keys }
+    }
+    /// Returns the ID of the primary key
+    pub fn primary_id(&self) -> KeyId {
+        self.primary
+    }
+    /// Returns the key with the given ID
+    pub fn get(&self, id: KeyId) -> Option<&SecretBox> {
+        self.keys.get(&id)
+    }
+    /// Returns the IDs of all keys in the keyring
+    pub fn ids(&self) -> impl Iterator<Item = KeyId> + '_ {
+        self.keys.keys().cloned()
+    }
+    /// Makes a new key the primary key. The previous primary key is retired, and can still be used
+    /// to open messages.
+    ///
+    /// Returns None if the ID is already in use
+    pub fn rotate(&mut self, id: KeyId, primary: SecretBox) -> Option<()> {
+        self.add_retired(id, primary)?;
+        self.primary = id;
+        Some(())
+    }
+    /// Adds a key that is only used to open messages
+    ///
+    /// Returns None if the ID is already in use
+    pub fn add_retired(&mut self, id: KeyId, key: SecretBox) -> Option<()> {
+        if self.keys.contains_key(&id) {
+            return None;
+        }
+        self.keys.insert(id, key);
+        Some(())
+    }
+    /// Removes a retired key. Messages sealed with it can no longer be opened.
+    ///
+    /// Returns None if the key does not exist or is the primary key
+    pub fn remove(&mut self, id: KeyId) -> Option<SecretBox> {
+        if id == self.primary {
+            return None;
+        }
+        self.keys.remove(&id)
+    }
+    /// Reads the key ID of a sealed message
+    pub fn key_id(data: &[u8]) -> Option<KeyId> {
+        if data.len() < KEY_ID_BYTES {
+            return None;
+        }
+        Some(KeyId::from_be_bytes([data[0], data[1], data[2], data[3]]))
+    }
+    /// Seals a message with the primary key and a random nonce
+    pub fn seal(&self, message: &[u8]) -> Vec<u8> {
+        let sealed = self.keys[&self.primary].easy_seal(message);
+        let mut out = Vec::with_capacity(sealed.len() + KEY_ID_BYTES);
+        out.extend_from_slice(&self.primary.to_be_bytes());
+        out.extend_from_slice(&sealed);
+        out
+    }
+    /// Opens a message with the key it was sealed with
+    ///
+    /// Returns None if the key is not in the keyring or the message is not authentic
+    pub fn open(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let key = self.keys.get(&Self::key_id(data)?)?;
+        key.easy_unseal(&data[KEY_ID_BYTES..])
+    }
+    /// Opens a message and seals it again with the primary key. Messages that already use the
+    /// primary key are returned unchanged.
+    ///
+    /// Returns None if the message cannot be opened
+    pub fn reseal(&self, data: &[u8]) -> Option<Vec<u8>> {
+        let message = self.open(data)?;
+        if Self::key_id(data)? == self.primary {
+            return Some(data.to_vec());
+        }
+        Some(self.seal(&message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CipherType;
+    #[test]
+    fn rotation() {
+        let old = SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap();
+        let new = SecretBox::new([2u8; 32], CipherType::Chacha20).unwrap();
+        let mut keyring = Keyring::new(1, old);
+        let sealed_old = keyring.seal(b"first quarter");
+        assert_eq!(Keyring::key_id(&sealed_old), Some(1));
+
+        assert_eq!(keyring.rotate(1, new), None);
+        keyring.rotate(2, new).unwrap();
+        assert_eq!(keyring.primary_id(), 2);
+        let sealed_new = keyring.seal(b"second quarter");
+        assert_eq!(Keyring::key_id(&sealed_new), Some(2));
+        assert_eq!(
+            keyring.open(&sealed_old).unwrap(),
+            b"first quarter".to_vec()
+        );
+        assert_eq!(
+            keyring.open(&sealed_new).unwrap(),
+            b"second quarter".to_vec()
+        );
+
+        let migrated = keyring.reseal(&sealed_old).unwrap();
+        assert_eq!(Keyring::key_id(&migrated), Some(2));
+        assert_eq!(keyring.reseal(&sealed_new).unwrap(), sealed_new);
+
+        assert_eq!(keyring.remove(2), None);
+        assert_eq!(keyring.remove(1), Some(old));
+        assert_eq!(keyring.open(&sealed_old), None);
+        assert_eq!(keyring.open(&migrated).unwrap(), b"first quarter".to_vec());
+        assert_eq!(keyring.ids().collect::<Vec<_>>(), vec![2]);
+    }
+    #[test]
+    fn malformed() {
+        let keyring = Keyring::new(1, SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap());
+        let mut sealed = keyring.seal(b"data");
+        assert_eq!(keyring.open(&sealed[..3]), None);
+        assert_eq!(keyring.open(&sealed[..10]), None);
+        sealed[3] = 9;
+        assert_eq!(keyring.open(&sealed), None);
+        sealed[3] = 1;
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert_eq!(keyring.open(&sealed), None);
+    }
+}
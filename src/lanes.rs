@@ -0,0 +1,331 @@
+//! Block functions evaluated for several independent inputs at once
+//!
+//! Small packets fit into one or two 64-byte blocks, so vectorizing a single keystream does not
+//! help them. Instead, the functions in this module process `LANES` unrelated (key, nonce) pairs
+//! side by side. The state is stored transposed: word `i` of every lane is kept in one `[u32;
+//! LANES]` array, so every step of the round functions is the same operation on all lanes, which
+//! the compiler turns into SIMD instructions. With AVX2 enabled at compile time, 8 lanes are used,
+//! otherwise 4.
+//!
+//! `SecretBox::seal_many` uses these functions to seal groups of small messages.
+use crate::batch::Nonce;
+use crate::{chacha20, kdf, salsa20, CipherType, FirstBlock, SecretBox};
+
+/// Number of inputs processed at once
+#[cfg(target_feature = "avx2")]
+pub const LANES: usize = 8;
+/// Number of inputs processed at once
+#[cfg(not(target_feature = "avx2"))]
+pub const LANES: usize = 4;
+
+/// One word of the state for every lane
+type Lane = [u32; LANES];
+
+#[inline(always)]
+fn zip_with<F>(mut a: Lane, b: Lane, f: F) -> Lane
+where
+    F: Fn(u32, u32) -> u32,
+{
+    for (x, y) in a.iter_mut().zip(b.iter()) {
+        *x = f(*x, *y);
+    }
+    a
+}
+
+#[inline(always)]
+fn add(a: Lane, b: Lane) -> Lane {
+    zip_with(a, b, u32::wrapping_add)
+}
+
+#[inline(always)]
+fn xor_rotl(a: Lane, b: Lane, n: u32) -> Lane {
+    zip_with(a, b, |x, y| (x ^ y).rotate_left(n))
+}
+
+#[inline(always)]
+fn salsa20_quarter_round(s: &mut [Lane; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[b] = zip_with(s[b], add(s[a], s[d]), |x, y| x ^ y.rotate_left(7));
+    s[c] = zip_with(s[c], add(s[b], s[a]), |x, y| x ^ y.rotate_left(9));
+    s[d] = zip_with(s[d], add(s[c], s[b]), |x, y| x ^ y.rotate_left(13));
+    s[a] = zip_with(s[a], add(s[d], s[c]), |x, y| x ^ y.rotate_left(18));
+}
+
+#[inline(always)]
+fn chacha20_quarter_round(s: &mut [Lane; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = add(s[a], s[b]);
+    s[d] = xor_rotl(s[d], s[a], 16);
+    s[c] = add(s[c], s[d]);
+    s[b] = xor_rotl(s[b], s[c], 12);
+    s[a] = add(s[a], s[b]);
+    s[d] = xor_rotl(s[d], s[a], 8);
+    s[c] = add(s[c], s[d]);
+    s[b] = xor_rotl(s[b], s[c], 7);
+}
+
+/// Transposes one state per lane into one lane array per word
+fn transpose_in(input: &[[u32; 16]; LANES]) -> [Lane; 16] {
+    let mut s = [[0u32; LANES]; 16];
+    for (lane, words) in input.iter().enumerate() {
+        for (word, w) in s.iter_mut().zip(words.iter()) {
+            word[lane] = *w;
+        }
+    }
+    s
+}
+
+/// Transposes the lane arrays back into one state per lane
+fn transpose_out(s: &[Lane; 16]) -> [[u32; 16]; LANES] {
+    let mut out = [[0u32; 16]; LANES];
+    for (lane, words) in out.iter_mut().enumerate() {
+        for (w, word) in words.iter_mut().zip(s.iter()) {
+            *w = word[lane];
+        }
+    }
+    out
+}
+
+/// Adds the input to the state after the rounds
+fn feed_forward(s: &mut [Lane; 16], input: &[Lane; 16]) {
+    for (x, y) in s.iter_mut().zip(input.iter()) {
+        *x = add(*x, *y);
+    }
+}
+
+/// Applies the 20 rounds of Salsa20 without adding the input, as needed by HSalsa20
+pub fn salsa20_rounds(input: &[[u32; 16]; LANES]) -> [[u32; 16]; LANES] {
+    let mut s = transpose_in(input);
+    for _ in 0..10 {
+        salsa20_quarter_round(&mut s, 0, 4, 8, 12);
+        salsa20_quarter_round(&mut s, 5, 9, 13, 1);
+        salsa20_quarter_round(&mut s, 10, 14, 2, 6);
+        salsa20_quarter_round(&mut s, 15, 3, 7, 11);
+        salsa20_quarter_round(&mut s, 0, 1, 2, 3);
+        salsa20_quarter_round(&mut s, 5, 6, 7, 4);
+        salsa20_quarter_round(&mut s, 10, 11, 8, 9);
+        salsa20_quarter_round(&mut s, 15, 12, 13, 14);
+    }
+    transpose_out(&s)
+}
+
+/// Computes the Salsa20 block function for every lane
+pub fn salsa20(input: &[[u32; 16]; LANES]) -> [[u32; 16]; LANES] {
+    let mut s = transpose_in(&salsa20_rounds(input));
+    feed_forward(&mut s, &transpose_in(input));
+    transpose_out(&s)
+}
+
+/// Computes the Chacha20 block function for every lane
+pub fn chacha20(input: &[[u32; 16]; LANES]) -> [[u32; 16]; LANES] {
+    let start = transpose_in(input);
+    let mut s = start;
+    for _ in 0..10 {
+        chacha20_quarter_round(&mut s, 0, 4, 8, 12);
+        chacha20_quarter_round(&mut s, 1, 5, 9, 13);
+        chacha20_quarter_round(&mut s, 2, 6, 10, 14);
+        chacha20_quarter_round(&mut s, 3, 7, 11, 15);
+        chacha20_quarter_round(&mut s, 0, 5, 10, 15);
+        chacha20_quarter_round(&mut s, 1, 6, 11, 12);
+        chacha20_quarter_round(&mut s, 2, 7, 8, 13);
+        chacha20_quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    feed_forward(&mut s, &start);
+    transpose_out(&s)
+}
+
+/// Computes HSalsa20 with the `SIGMA` constant for every lane
+pub fn hsalsa20(data: &[[u8; 16]; LANES], keys: &[[u8; 32]; LANES]) -> [[u8; 32]; LANES] {
+    let mut input = [[0u32; 16]; LANES];
+    for (i, (d, k)) in input.iter_mut().zip(data.iter().zip(keys.iter())) {
+        *i = kdf::hsalsa20_input(*d, *k, kdf::SIGMA);
+    }
+    let mut out = [[0u8; 32]; LANES];
+    for (o, s) in out.iter_mut().zip(salsa20_rounds(&input).iter()) {
+        *o = kdf::hsalsa20_output(*s);
+    }
+    out
+}
+
+/// XORs as much of the keystream into the data as fits
+fn xor_keystream(data: &mut [u8], keystream: &[u8]) {
+    for (d, k) in data.iter_mut().zip(keystream.iter()) {
+        *d ^= k;
+    }
+}
+
+impl SecretBox {
+    /// Generates keystream block `ctr` for every lane
+    fn lane_blocks(
+        &self,
+        sub_keys: &[[u8; 32]; LANES],
+        nonces: &[[u8; 8]; LANES],
+        ctr: u64,
+    ) -> [[u8; 64]; LANES] {
+        let mut input = [[0u32; 16]; LANES];
+        for (i, (k, n)) in input.iter_mut().zip(sub_keys.iter().zip(nonces.iter())) {
+            *i = match self.cipher {
+                CipherType::Chacha20 => chacha20::XChacha20::new(*k, *n, ctr).state(),
+                CipherType::Salsa20 => salsa20::XSalsa20::new(*k, *n, ctr).state(),
+            };
+        }
+        let output = match self.cipher {
+            CipherType::Chacha20 => chacha20(&input),
+            CipherType::Salsa20 => salsa20(&input),
+        };
+        let mut blocks = [[0u8; 64]; LANES];
+        for (block, words) in blocks.iter_mut().zip(output.iter()) {
+            for (b, w) in block.chunks_mut(4).zip(words.iter()) {
+                b.copy_from_slice(&w.to_le_bytes());
+            }
+        }
+        blocks
+    }
+
+    /// Computes the subkeys and first keystream blocks for the nonces of up to `LANES` messages
+    /// at once. Unused lanes are filled with the results for a zero nonce.
+    pub(crate) fn first_blocks(&self, group: &[(&[u8], Nonce)]) -> [FirstBlock; LANES] {
+        let mut hnonces = [[0u8; 16]; LANES];
+        let mut nonces = [[0u8; 8]; LANES];
+        for (i, (_, nonce)) in group.iter().enumerate() {
+            hnonces[i].copy_from_slice(&nonce[..16]);
+            nonces[i].copy_from_slice(&nonce[16..]);
+        }
+        let sub_keys = hsalsa20(&hnonces, &[self.key; LANES]);
+        let blocks = self.lane_blocks(&sub_keys, &nonces, 0);
+        let mut out = [([0u8; 32], [0u8; 8], [0u8; 64]); LANES];
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = (sub_keys[i], nonces[i], blocks[i]);
+        }
+        out
+    }
+
+    /// Seals up to `LANES` messages using the lane functions
+    fn seal_lanes(&self, group: &[(&[u8], Nonce)]) -> Vec<Vec<u8>> {
+        // The first block holds the Poly1305 key and the keystream for the first 32 bytes
+        let first = self.first_blocks(group);
+        let mut sub_keys = [[0u8; 32]; LANES];
+        let mut nonces = [[0u8; 8]; LANES];
+        for (i, (sub_key, nonce, _)) in first.iter().enumerate() {
+            sub_keys[i] = *sub_key;
+            nonces[i] = *nonce;
+        }
+        let mut boxes: Vec<Vec<u8>> = group
+            .iter()
+            .map(|(m, _)| {
+                let mut b = vec![0u8; m.len() + 16];
+                b[16..].copy_from_slice(m);
+                b
+            })
+            .collect();
+        let mut poly1305_keys = [[0u8; 32]; LANES];
+        for (i, b) in boxes.iter_mut().enumerate() {
+            poly1305_keys[i].copy_from_slice(&first[i].2[..32]);
+            xor_keystream(&mut b[16..], &first[i].2[32..]);
+        }
+        // Block `ctr` covers bytes 32 + 64 * (ctr - 1) onwards. Lanes are used as long as at
+        // least two messages need more keystream, the last long message is finished alone.
+        let offset = |ctr: u64| 16 + 32 + 64 * (ctr as usize - 1);
+        let mut ctr = 1;
+        while boxes.iter().filter(|b| b.len() > offset(ctr)).count() >= 2 {
+            let blocks = self.lane_blocks(&sub_keys, &nonces, ctr);
+            for (b, block) in boxes.iter_mut().zip(blocks.iter()) {
+                if b.len() > offset(ctr) {
+                    let start = offset(ctr);
+                    xor_keystream(&mut b[start..], block);
+                }
+            }
+            ctr += 1;
+        }
+        for (i, b) in boxes.iter_mut().enumerate() {
+            if b.len() > offset(ctr) {
+                let start = offset(ctr);
+                match self.cipher {
+                    CipherType::Chacha20 => {
+                        chacha20::XChacha20::new(sub_keys[i], nonces[i], ctr).crypt(&mut b[start..])
+                    }
+                    CipherType::Salsa20 => {
+                        salsa20::XSalsa20::new(sub_keys[i], nonces[i], ctr).crypt(&mut b[start..])
+                    }
+                };
+            }
+        }
+
+        for (b, key) in boxes.iter_mut().zip(poly1305_keys.iter()) {
+            let tag = crate::poly1305_from_key(*key).hash(&b[16..]);
+            b[..16].copy_from_slice(&tag.to_le_bytes());
+        }
+        boxes
+    }
+
+    /// Seals several independent messages, each with its own nonce, and returns the boxes in
+    /// the same order. The output is identical to calling `seal` for every message.
+    ///
+    /// Messages are processed in groups of `LANES`, sharing the work of the HSalsa20 subkey
+    /// derivation and the keystream blocks between the messages of a group. This is most useful
+    /// for many small messages, like network packets.
+    pub fn seal_many(&self, messages: &[(&[u8], Nonce)]) -> Vec<Vec<u8>> {
+        let mut out = Vec::with_capacity(messages.len());
+        for group in messages.chunks(LANES) {
+            if group.len() == 1 {
+                out.push(self.seal(group[0].0, group[0].1));
+            } else {
+                out.extend(self.seal_lanes(group));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs() -> [[u32; 16]; LANES] {
+        let mut input = [[0u32; 16]; LANES];
+        for (l, words) in input.iter_mut().enumerate() {
+            for (i, w) in words.iter_mut().enumerate() {
+                *w = ((l * 16 + i) as u32).wrapping_mul(0x9e37_79b9);
+            }
+        }
+        input
+    }
+
+    #[test]
+    fn block_functions() {
+        let input = inputs();
+        let salsa = salsa20(&input);
+        let chacha = chacha20(&input);
+        for l in 0..LANES {
+            assert_eq!(salsa[l], salsa20::salsa20(input[l]));
+            assert_eq!(chacha[l], chacha20::chacha20(input[l]));
+        }
+        let mut data = [[0u8; 16]; LANES];
+        let mut keys = [[0u8; 32]; LANES];
+        for l in 0..LANES {
+            data[l] = [l as u8; 16];
+            keys[l] = [(l * 3) as u8; 32];
+        }
+        let out = hsalsa20(&data, &keys);
+        for l in 0..LANES {
+            assert_eq!(out[l], kdf::hsalsa20(data[l], keys[l], kdf::SIGMA));
+        }
+    }
+
+    #[test]
+    fn seal_many() {
+        for &cipher in [CipherType::Salsa20, CipherType::Chacha20].iter() {
+            let s = SecretBox::new([5u8; 32], cipher).unwrap();
+            let lens = [0, 1, 31, 32, 33, 95, 96, 97, 200, 7, 1000, 64, 3];
+            let records: Vec<Vec<u8>> = lens.iter().map(|&n| vec![n as u8; n]).collect();
+            let messages: Vec<(&[u8], Nonce)> = records
+                .iter()
+                .enumerate()
+                .map(|(i, r)| (&r[..], [i as u8 + 1; 24]))
+                .collect();
+            let boxes = s.seal_many(&messages);
+            assert_eq!(boxes.len(), messages.len());
+            for (b, (m, nonce)) in boxes.iter().zip(messages.iter()) {
+                assert_eq!(b, &s.seal(m, *nonce));
+            }
+        }
+    }
+}
@@ -1,20 +1,98 @@
 #[macro_use]
 extern crate uint;
+pub mod batch;
+pub mod blake2b;
+#[cfg(feature = "bytes")]
+pub mod bytes_mut;
 pub mod chacha20;
+pub mod chunked;
+pub mod commit;
+#[cfg(feature = "curve25519")]
+pub mod crypto_box;
 pub mod csprng;
-pub(crate) mod kdf;
+pub mod envelope;
+pub mod kdf;
+pub mod keyring;
+pub mod lanes;
+pub mod nonce;
+pub mod padding;
 pub mod poly1305;
+pub mod provider;
+pub mod pwhash;
+pub mod recipients;
+pub mod replay;
+pub mod rtp;
 pub mod salsa20;
+pub mod scrypt;
+pub mod sha256;
+pub mod siv;
+pub mod vectored;
+pub mod wrap;
 construct_uint! {
     pub struct U256(4);
 }
 
+/// Compares two slices without leaking the position of the first difference through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum CipherType {
     Salsa20,
     Chacha20,
 }
 
+impl CipherType {
+    /// Returns the algorithm ID used in serialized formats
+    pub fn id(self) -> u8 {
+        match self {
+            CipherType::Salsa20 => 1,
+            CipherType::Chacha20 => 2,
+        }
+    }
+    /// Returns the cipher with the given algorithm ID
+    ///
+    /// Formats that record an algorithm ID (envelopes, wrapped keys and chunked files) are opened
+    /// with the cipher looked up here, whatever cipher the opening `SecretBox` was created with.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(CipherType::Salsa20),
+            2 => Some(CipherType::Chacha20),
+            _ => None,
+        }
+    }
+}
+
+/// Placement of the nonce in the output of `SecretBox::easy_seal_with`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EasyLayout {
+    /// The nonce is prepended to the box, like `easy_seal` does
+    Prefix,
+    /// The nonce is appended to the box
+    Suffix,
+    /// Only the given number of low bytes (1 to 8) of a counter nonce are prepended to the box.
+    /// The nonce is restored as `nonce::make_nonce([0; 16], counter)`, which is the form
+    /// produced by `nonce::CounterNonce`.
+    TruncatedCounter(usize),
+}
+
+impl EasyLayout {
+    /// Returns the number of nonce bytes stored in the output
+    ///
+    /// Returns None if the truncated counter length is not between 1 and 8
+    pub fn nonce_len(self) -> Option<usize> {
+        match self {
+            EasyLayout::Prefix | EasyLayout::Suffix => Some(24),
+            EasyLayout::TruncatedCounter(n) if (1..=8).contains(&n) => Some(n),
+            EasyLayout::TruncatedCounter(_) => None,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct SecretBox {
     key: [u8; 32],
@@ -44,32 +122,27 @@ impl SecretBox {
     }
     /// Creates a new SecretBox instance by doing an ECDH key exchange using curve25519
     ///
-    /// Returns None if the passed public key is not 32 bytes long.
+    /// A new ephemeral key pair is generated for the exchange and the shared secret is hashed
+    /// with HSalsa20 like `crypto_box_beforenm` does. The ephemeral public key is returned
+    /// alongside the box and needs to be sent to the peer.
+    ///
+    /// Returns None if the passed public key is not 32 bytes long or is a low-order point.
     #[cfg(feature = "curve25519")]
     pub fn from_ecdh<T, R>(
         peer_public_key: T,
         rng: &mut R,
-        cipher: CiperType,
+        cipher: CipherType,
     ) -> Option<(Self, [u8; 32])>
     where
         T: AsRef<[u8]>,
         R: rand::Rng + rand::CryptoRng,
     {
-        let k = peer_public_key.as_ref();
-        if k.len() != 32 {
-            return None;
-        }
-        let peer_pubkey: x25519_dalek::PublicKey = From::from([
-            k[0], k[1], k[2], k[3], k[4], k[5], k[6], k[7], k[8], k[9], k[10], k[11], k[12], k[13],
-            k[14], k[15], k[16], k[17], k[18], k[19], k[20], k[21], k[22], k[23], k[24], k[25],
-            k[26], k[27], k[28], k[29], k[30], k[31],
-        ]);
-        let privkey = x25519_dalek::EphermalSecret::new(rng);
-        let pubkey: x25519_dalek::PublicĶey = From::from(&privkey);
-        let shared_secret = privkey.diffie_hellman(&peer_pubkey);
+        let peer_pubkey = crypto_box::PublicKey::from_slice(peer_public_key.as_ref())?;
+        let (pubkey, privkey) = crypto_box::keypair(rng);
+        let shared = crypto_box::beforenm(&peer_pubkey, &privkey)?;
         Some((
             Self {
-                key: *shared_secret.as_bytes(),
+                key: shared.key,
                 cipher,
             },
             *pubkey.as_bytes(),
@@ -84,76 +157,192 @@ impl SecretBox {
         rng.fill_bytes(&mut buf);
         (Self { key: buf, cipher }, buf)
     }
+    /// Creates a new SecretBox instance with a subkey derived from a master key
+    ///
+    /// See `kdf::derive_from_key` for the meaning of `subkey_id` and `context`.
+    pub fn derive(master: &[u8; 32], subkey_id: u64, context: [u8; 8], cipher: CipherType) -> Self {
+        Self {
+            key: kdf::derive_from_key(master, subkey_id, context),
+            cipher,
+        }
+    }
+    /// Creates a new SecretBox instance with a key derived from a password using scrypt
+    ///
+    /// The salt should be random and stored alongside the encrypted data.
+    pub fn from_password<P, S>(
+        password: P,
+        salt: S,
+        params: &scrypt::Params,
+        cipher: CipherType,
+    ) -> Self
+    where
+        P: AsRef<[u8]>,
+        S: AsRef<[u8]>,
+    {
+        let mut key = [0u8; 32];
+        scrypt::scrypt(password.as_ref(), salt.as_ref(), params, &mut key);
+        Self { key, cipher }
+    }
     /// This function returns an encrypted and authenticated copy of the message. The key and nonce
     /// pair must be unique for every message.
     pub fn seal(&self, message: &[u8], nonce: [u8; 24]) -> Vec<u8> {
-        let mut out = Vec::with_capacity(message.len() + 16);
-        out.extend_from_slice(&[0u8; 16]);
+        let mut out = vec![0u8; message.len() + 16];
+        self.seal_into(message, nonce, &mut out);
+        out
+    }
+
+    /// Generates the subkey and the first keystream block, which holds the Poly1305 key
+    /// followed by the keystream for the first 32 bytes of the message.
+    fn first_block(&self, nonce: [u8; 24]) -> FirstBlock {
         let (sub_key, nonce) = kdf::generate_subkey(nonce, self.key);
         // The poly1305 key is generated by encrypting 32 bytes of zeroes.
         // The chosen encryption method works with 64-byte blocks
-        let initial_block = match self.cipher {
-            CipherType::Chacha20 => chacha20::XChacha20::new(sub_key, nonce, 0).generate_block(),
-            CipherType::Salsa20 => salsa20::XSalsa20::new(sub_key, nonce, 0).generate_block(),
+        let words = match self.cipher {
+            CipherType::Chacha20 => {
+                chacha20::chacha20(chacha20::XChacha20::new(sub_key, nonce, 0).state())
+            }
+            CipherType::Salsa20 => {
+                salsa20::salsa20(salsa20::XSalsa20::new(sub_key, nonce, 0).state())
+            }
+        };
+        let mut initial_block = [0u8; 64];
+        for (b, w) in initial_block.chunks_mut(4).zip(words.iter()) {
+            b.copy_from_slice(&w.to_le_bytes());
+        }
+        (sub_key, nonce, initial_block)
+    }
+
+    /// Creates the stream cipher for the message, starting after the first keystream block
+    fn keystream(&self, sub_key: [u8; 32], nonce: [u8; 8]) -> Keystream {
+        match self.cipher {
+            CipherType::Chacha20 => {
+                Keystream::Chacha20(chacha20::XChacha20::new(sub_key, nonce, 1))
+            }
+            CipherType::Salsa20 => Keystream::Salsa20(salsa20::XSalsa20::new(sub_key, nonce, 1)),
         }
-        .unwrap();
-        let poly1305_r = u128::from_le_bytes([
-            initial_block[0],
-            initial_block[1],
-            initial_block[2],
-            initial_block[3],
-            initial_block[4],
-            initial_block[5],
-            initial_block[6],
-            initial_block[7],
-            initial_block[8],
-            initial_block[9],
-            initial_block[10],
-            initial_block[11],
-            initial_block[12],
-            initial_block[13],
-            initial_block[14],
-            initial_block[15],
-        ]);
-        let poly1305_s = u128::from_le_bytes([
-            initial_block[16],
-            initial_block[17],
-            initial_block[18],
-            initial_block[19],
-            initial_block[20],
-            initial_block[21],
-            initial_block[22],
-            initial_block[23],
-            initial_block[24],
-            initial_block[25],
-            initial_block[26],
-            initial_block[27],
-            initial_block[28],
-            initial_block[29],
-            initial_block[30],
-            initial_block[31],
-        ]);
-        let mut mac = poly1305::Poly1305::new(poly1305_r, poly1305_s);
+    }
+
+    /// Seals a message into `out`, which must be exactly 16 bytes longer than the message
+    pub(crate) fn seal_into(&self, message: &[u8], nonce: [u8; 24], out: &mut [u8]) {
+        assert_eq!(out.len(), message.len() + 16);
+        let (tag, body) = out.split_at_mut(16);
+        tag.copy_from_slice(&self.seal_detached(nonce, Some(message), body));
+    }
+
+    /// Encrypts and authenticates `body` in place and returns the tag. If a message is passed,
+    /// it is copied into `body` first, which must have the same length.
+    ///
+    /// Every chunk is encrypted and immediately authenticated while it is still in the cache, so
+    /// the message is only read and written once.
+    pub(crate) fn seal_detached(
+        &self,
+        nonce: [u8; 24],
+        message: Option<&[u8]>,
+        body: &mut [u8],
+    ) -> [u8; 16] {
+        self.seal_detached_from(self.first_block(nonce), message, body)
+    }
+
+    /// Works like `seal_detached`, with the subkey and first block already computed
+    pub(crate) fn seal_detached_from(
+        &self,
+        first_block: FirstBlock,
+        message: Option<&[u8]>,
+        body: &mut [u8],
+    ) -> [u8; 16] {
+        let (sub_key, nonce, initial_block) = first_block;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&initial_block[..32]);
+        let mut mac = poly1305_from_key(key);
         // Encrypt 32 bytes
-        for i in 0..std::cmp::min(32, message.len()) {
-            out.push(initial_block[32 + i] ^ message[i]);
+        let head = std::cmp::min(32, body.len());
+        let (first, rest) = body.split_at_mut(head);
+        if let Some(message) = message {
+            first.copy_from_slice(&message[..head]);
         }
-        if message.len() > 32 {
-            out.extend_from_slice(&message[32..]);
-            match self.cipher {
-                CipherType::Chacha20 => {
-                    chacha20::XChacha20::new(sub_key, nonce, 1).crypt(&mut out[48..])
-                }
-                CipherType::Salsa20 => {
-                    salsa20::XSalsa20::new(sub_key, nonce, 1).crypt(&mut out[48..])
-                }
-            };
+        for (b, k) in first.iter_mut().zip(&initial_block[32..]) {
+            *b ^= k;
         }
-        let mac = mac.hash(&out[16..]);
-        for (i, c) in mac.to_le_bytes().into_iter().enumerate() {
-            out[i] = *c;
+        mac.update(first);
+        let mut keystream = self.keystream(sub_key, nonce);
+        for (i, chunk) in rest.chunks_mut(CHUNK_BYTES).enumerate() {
+            if let Some(message) = message {
+                let start = head + i * CHUNK_BYTES;
+                chunk.copy_from_slice(&message[start..start + chunk.len()]);
+            }
+            keystream.crypt(chunk);
+            mac.update(chunk);
         }
-        out
+        mac.finalize().to_le_bytes()
+    }
+
+    /// Authenticates a box and decrypts it into `out`, which must be exactly 16 bytes shorter
+    /// than the box.
+    ///
+    /// If the box turns out not to be authentic, `out` is overwritten with zeroes and None is
+    /// returned.
+    pub(crate) fn unseal_into(&self, data: &[u8], nonce: [u8; 24], out: &mut [u8]) -> Option<()> {
+        if data.len() < 16 {
+            return None;
+        }
+        assert_eq!(out.len(), data.len() - 16);
+        let (tag, ciphertext) = data.split_at(16);
+        self.open_detached(nonce, tag, Some(ciphertext), out)
+    }
+
+    /// Authenticates and decrypts `body` in place. If a ciphertext is passed, it is copied into
+    /// `body` first, which must have the same length.
+    ///
+    /// Like `seal_detached`, authentication and decryption happen in a single pass. If the tag
+    /// does not match, `body` is overwritten with zeroes and None is returned.
+    pub(crate) fn open_detached(
+        &self,
+        nonce: [u8; 24],
+        tag: &[u8],
+        ciphertext: Option<&[u8]>,
+        body: &mut [u8],
+    ) -> Option<()> {
+        self.open_detached_from(self.first_block(nonce), tag, ciphertext, body)
+    }
+
+    /// Works like `open_detached`, with the subkey and first block already computed
+    pub(crate) fn open_detached_from(
+        &self,
+        first_block: FirstBlock,
+        tag: &[u8],
+        ciphertext: Option<&[u8]>,
+        body: &mut [u8],
+    ) -> Option<()> {
+        let (sub_key, nonce, initial_block) = first_block;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&initial_block[..32]);
+        let mut mac = poly1305_from_key(key);
+        // Decrypt 32 bytes
+        let head = std::cmp::min(32, body.len());
+        let (first, rest) = body.split_at_mut(head);
+        if let Some(ciphertext) = ciphertext {
+            first.copy_from_slice(&ciphertext[..head]);
+        }
+        mac.update(first);
+        for (b, k) in first.iter_mut().zip(&initial_block[32..]) {
+            *b ^= k;
+        }
+        let mut keystream = self.keystream(sub_key, nonce);
+        for (i, chunk) in rest.chunks_mut(CHUNK_BYTES).enumerate() {
+            if let Some(ciphertext) = ciphertext {
+                let start = head + i * CHUNK_BYTES;
+                chunk.copy_from_slice(&ciphertext[start..start + chunk.len()]);
+            }
+            mac.update(chunk);
+            keystream.crypt(chunk);
+        }
+        if !constant_time_eq(&mac.finalize().to_le_bytes(), tag) {
+            for b in body.iter_mut() {
+                *b = 0;
+            }
+            return None;
+        }
+        Some(())
     }
 
     /// This function works like the above, except that it automatically generates a unique nonce.
@@ -170,6 +359,9 @@ impl SecretBox {
 
     /// This function works like unseal, except that it finds the nonce automatically.
     pub fn easy_unseal(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < 24 {
+            return None;
+        }
         let nonce = [
             data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7], data[8],
             data[9], data[10], data[11], data[12], data[13], data[14], data[15], data[16],
@@ -178,80 +370,120 @@ impl SecretBox {
         self.unseal(&data[24..], nonce)
     }
 
-    /// This function tries to authenticate and decrypt a box
-    pub fn unseal(&self, data: &[u8], nonce: [u8; 24]) -> Option<Vec<u8>> {
-        let mut out = Vec::with_capacity(data.len() - 16);
-        let (sub_key, nonce) = kdf::generate_subkey(nonce, self.key);
-        // The poly1305 key is generated by encrypting 32 bytes of zeroes.
-        // The chosen encryption method works with 64-byte blocks
-        let initial_block = match self.cipher {
-            CipherType::Chacha20 => chacha20::XChacha20::new(sub_key, nonce, 0).generate_block(),
-            CipherType::Salsa20 => salsa20::XSalsa20::new(sub_key, nonce, 0).generate_block(),
+    /// Seals a message with the given nonce and stores the nonce as described by the layout
+    ///
+    /// Returns None if the layout is invalid, or if it uses a truncated counter and the nonce is
+    /// not a counter nonce whose counter fits into the truncated length.
+    pub fn easy_seal_with(
+        &self,
+        message: &[u8],
+        nonce: [u8; 24],
+        layout: EasyLayout,
+    ) -> Option<Vec<u8>> {
+        let nonce_len = layout.nonce_len()?;
+        if let EasyLayout::TruncatedCounter(n) = layout {
+            let (prefix, counter) = nonce::split_nonce(nonce);
+            if prefix != [0u8; nonce::PREFIX_BYTES] || (n < 8 && counter >> (8 * n) != 0) {
+                return None;
+            }
         }
-        .unwrap();
-        let poly1305_r = u128::from_le_bytes([
-            initial_block[0],
-            initial_block[1],
-            initial_block[2],
-            initial_block[3],
-            initial_block[4],
-            initial_block[5],
-            initial_block[6],
-            initial_block[7],
-            initial_block[8],
-            initial_block[9],
-            initial_block[10],
-            initial_block[11],
-            initial_block[12],
-            initial_block[13],
-            initial_block[14],
-            initial_block[15],
-        ]);
-        let poly1305_s = u128::from_le_bytes([
-            initial_block[16],
-            initial_block[17],
-            initial_block[18],
-            initial_block[19],
-            initial_block[20],
-            initial_block[21],
-            initial_block[22],
-            initial_block[23],
-            initial_block[24],
-            initial_block[25],
-            initial_block[26],
-            initial_block[27],
-            initial_block[28],
-            initial_block[29],
-            initial_block[30],
-            initial_block[31],
-        ]);
-        let expected_mac = u128::from_le_bytes([
-            data[0], data[1], data[2], data[3], data[4], data[5], data[6], data[7], data[8],
-            data[9], data[10], data[11], data[12], data[13], data[14], data[15],
-        ]);
-        let mut mac = poly1305::Poly1305::new(poly1305_r, poly1305_s);
-        if !mac.verify(&data[16..], expected_mac) {
-            return None;
+        let sealed = self.seal(message, nonce);
+        let mut v = Vec::with_capacity(sealed.len() + nonce_len);
+        match layout {
+            EasyLayout::Prefix => {
+                v.extend_from_slice(&nonce);
+                v.extend_from_slice(&sealed);
+            }
+            EasyLayout::Suffix => {
+                v.extend_from_slice(&sealed);
+                v.extend_from_slice(&nonce);
+            }
+            EasyLayout::TruncatedCounter(n) => {
+                v.extend_from_slice(&nonce[nonce::PREFIX_BYTES..nonce::PREFIX_BYTES + n]);
+                v.extend_from_slice(&sealed);
+            }
         }
-        // Encrypt 32 bytes
-        for i in 0..std::cmp::min(32, data.len() - 16) {
-            out.push(initial_block[32 + i] ^ data[16 + i]);
+        Some(v)
+    }
+
+    /// Opens a box created by `easy_seal_with` with the same layout
+    ///
+    /// Returns None if the layout is invalid, the data is too short or the box is not authentic
+    pub fn easy_unseal_with(&self, data: &[u8], layout: EasyLayout) -> Option<Vec<u8>> {
+        let nonce_len = layout.nonce_len()?;
+        if data.len() < nonce_len + 16 {
+            return None;
         }
-        if data.len() > 32 {
-            out.extend_from_slice(&data[48..]);
-            match self.cipher {
-                CipherType::Chacha20 => {
-                    chacha20::XChacha20::new(sub_key, nonce, 1).crypt(&mut out[32..])
-                }
-                CipherType::Salsa20 => {
-                    salsa20::XSalsa20::new(sub_key, nonce, 1).crypt(&mut out[32..])
-                }
-            };
+        let mut nonce = [0u8; 24];
+        let sealed = match layout {
+            EasyLayout::Prefix => {
+                nonce.copy_from_slice(&data[..24]);
+                &data[24..]
+            }
+            EasyLayout::Suffix => {
+                let split = data.len() - 24;
+                nonce.copy_from_slice(&data[split..]);
+                &data[..split]
+            }
+            EasyLayout::TruncatedCounter(n) => {
+                nonce[nonce::PREFIX_BYTES..nonce::PREFIX_BYTES + n].copy_from_slice(&data[..n]);
+                &data[n..]
+            }
+        };
+        self.unseal(sealed, nonce)
+    }
+
+    /// This function tries to authenticate and decrypt a box
+    pub fn unseal(&self, data: &[u8], nonce: [u8; 24]) -> Option<Vec<u8>> {
+        if data.len() < 16 {
+            return None;
         }
+        let mut out = vec![0u8; data.len() - 16];
+        self.unseal_into(data, nonce, &mut out)?;
         Some(out)
     }
 }
 
+/// Size of the chunks that are encrypted and authenticated together. It is a multiple of the
+/// block size of the ciphers and of Poly1305, and small enough to stay in the L1 cache.
+const CHUNK_BYTES: usize = 4096;
+
+/// Subkey, nonce and first keystream block of a box
+pub(crate) type FirstBlock = ([u8; 32], [u8; 8], [u8; 64]);
+
+/// Stream cipher of a box, after the first block
+enum Keystream {
+    Salsa20(salsa20::XSalsa20),
+    Chacha20(chacha20::XChacha20),
+}
+
+impl Keystream {
+    /// Encrypts the data in place. Every call except the last must pass a multiple of 64 bytes.
+    fn crypt(&mut self, data: &mut [u8]) {
+        match self {
+            Keystream::Salsa20(c) => c.crypt(data),
+            Keystream::Chacha20(c) => c.crypt(data),
+        };
+    }
+    /// Generates the next block of the keystream
+    fn generate_block(&mut self) -> Vec<u8> {
+        match self {
+            Keystream::Salsa20(c) => c.generate_block(),
+            Keystream::Chacha20(c) => c.generate_block(),
+        }
+        .unwrap()
+    }
+}
+
+/// Creates a Poly1305 instance from the 32-byte one-time key (r followed by s)
+fn poly1305_from_key(key: [u8; 32]) -> poly1305::Poly1305 {
+    let mut r = [0u8; 16];
+    let mut s = [0u8; 16];
+    r.copy_from_slice(&key[..16]);
+    s.copy_from_slice(&key[16..]);
+    poly1305::Poly1305::new(u128::from_le_bytes(r), u128::from_le_bytes(s))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -281,4 +513,149 @@ mod tests {
         let unsealed = s.easy_unseal(&sealed).unwrap();
         assert_eq!(&unsealed[..], &plain[..]);
     }
+
+    #[test]
+    fn short_messages() {
+        let s = SecretBox::new([1u8; 32], CipherType::Chacha20).unwrap();
+        for len in 0..100 {
+            let plain = vec![0x55u8; len];
+            let sealed = s.seal(&plain, [2u8; 24]);
+            assert_eq!(sealed.len(), len + 16);
+            assert_eq!(s.unseal(&sealed, [2u8; 24]).unwrap(), plain);
+        }
+        assert_eq!(s.unseal(&[0u8; 15], [2u8; 24]), None);
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        let key = [4u8; 32];
+        let nonce = [5u8; 24];
+        for &cipher in [CipherType::Salsa20, CipherType::Chacha20].iter() {
+            let s = SecretBox::new(key, cipher).unwrap();
+            for &len in [
+                CHUNK_BYTES + 31,
+                CHUNK_BYTES + 32,
+                CHUNK_BYTES + 33,
+                3 * CHUNK_BYTES,
+            ]
+            .iter()
+            {
+                let plain: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                // Encrypt with the whole keystream at once, then authenticate
+                let (sub_key, n) = kdf::generate_subkey(nonce, key);
+                let keystream = match cipher {
+                    CipherType::Chacha20 => {
+                        chacha20::XChacha20::new(sub_key, n, 0).generate(len + 32)
+                    }
+                    CipherType::Salsa20 => salsa20::XSalsa20::new(sub_key, n, 0).generate(len + 32),
+                }
+                .unwrap();
+                let ciphertext: Vec<u8> = plain
+                    .iter()
+                    .zip(&keystream[32..])
+                    .map(|(p, k)| p ^ k)
+                    .collect();
+                let mut poly1305_key = [0u8; 32];
+                poly1305_key.copy_from_slice(&keystream[..32]);
+                let tag = poly1305_from_key(poly1305_key).hash(&ciphertext);
+
+                let sealed = s.seal(&plain, nonce);
+                assert_eq!(&sealed[..16], &tag.to_le_bytes()[..]);
+                assert_eq!(&sealed[16..], &ciphertext[..]);
+                assert_eq!(s.unseal(&sealed, nonce).unwrap(), plain);
+                let mut forged = sealed;
+                let last = forged.len() - 1;
+                forged[last] ^= 1;
+                assert_eq!(s.unseal(&forged, nonce), None);
+            }
+        }
+    }
+
+    #[test]
+    fn easy_layouts() {
+        let s = SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap();
+        let nonce = [7u8; 24];
+        let prefix = s.easy_seal_with(b"hi", nonce, EasyLayout::Prefix).unwrap();
+        assert_eq!(s.easy_unseal(&prefix).unwrap(), b"hi".to_vec());
+        let suffix = s.easy_seal_with(b"hi", nonce, EasyLayout::Suffix).unwrap();
+        assert_eq!(&suffix[..18], &prefix[24..]);
+        assert_eq!(&suffix[18..], &nonce[..]);
+        assert_eq!(
+            s.easy_unseal_with(&suffix, EasyLayout::Suffix).unwrap(),
+            b"hi".to_vec()
+        );
+        assert_eq!(s.easy_unseal_with(&suffix, EasyLayout::Prefix), None);
+        assert_eq!(s.easy_unseal_with(&suffix[..39], EasyLayout::Suffix), None);
+
+        let layout = EasyLayout::TruncatedCounter(2);
+        let counter = nonce::make_nonce([0; 16], 0x1234);
+        let truncated = s.easy_seal_with(b"hi", counter, layout).unwrap();
+        assert_eq!(truncated.len(), 2 + 16 + 2);
+        assert_eq!(&truncated[..2], &[0x34, 0x12]);
+        assert_eq!(
+            s.easy_unseal_with(&truncated, layout).unwrap(),
+            b"hi".to_vec()
+        );
+        assert_eq!(s.easy_unseal_with(&truncated[..17], layout), None);
+        // Nonces that cannot be restored from the truncated counter are rejected
+        assert_eq!(s.easy_seal_with(b"hi", nonce, layout), None);
+        let large = nonce::make_nonce([0; 16], 0x10000);
+        assert_eq!(s.easy_seal_with(b"hi", large, layout), None);
+        let full = nonce::make_nonce([0; 16], u64::MAX);
+        let layout = EasyLayout::TruncatedCounter(8);
+        let sealed = s.easy_seal_with(b"hi", full, layout).unwrap();
+        assert_eq!(s.easy_unseal_with(&sealed, layout).unwrap(), b"hi".to_vec());
+        for &n in [0, 9].iter() {
+            let layout = EasyLayout::TruncatedCounter(n);
+            assert_eq!(layout.nonce_len(), None);
+            assert_eq!(s.easy_seal_with(b"hi", counter, layout), None);
+            assert_eq!(s.easy_unseal_with(&truncated, layout), None);
+        }
+    }
+
+    #[test]
+    fn derive() {
+        let master = [3u8; 32];
+        let s = SecretBox::derive(&master, 7, *b"Invoices", CipherType::Chacha20);
+        let sealed = s.seal(b"tenant data", [0u8; 24]);
+        let s = SecretBox::derive(&master, 7, *b"Invoices", CipherType::Chacha20);
+        assert_eq!(
+            s.unseal(&sealed, [0u8; 24]).unwrap(),
+            b"tenant data".to_vec()
+        );
+        let s = SecretBox::derive(&master, 8, *b"Invoices", CipherType::Chacha20);
+        assert_eq!(s.unseal(&sealed, [0u8; 24]), None);
+    }
+
+    #[test]
+    fn password() {
+        let params = scrypt::Params::new(10, 8, 1).unwrap();
+        let s =
+            SecretBox::from_password("hunter2", b"0123456789abcdef", &params, CipherType::Salsa20);
+        let sealed = s.easy_seal(b"config");
+        let s =
+            SecretBox::from_password("hunter2", b"0123456789abcdef", &params, CipherType::Salsa20);
+        assert_eq!(s.easy_unseal(&sealed).unwrap(), b"config".to_vec());
+        let s =
+            SecretBox::from_password("hunter3", b"0123456789abcdef", &params, CipherType::Salsa20);
+        assert_eq!(s.easy_unseal(&sealed), None);
+    }
+
+    #[cfg(feature = "curve25519")]
+    #[test]
+    fn ecdh() {
+        let mut rng = rand::rngs::OsRng;
+        let (pk, sk) = crypto_box::keypair(&mut rng);
+        let (s, ephemeral) =
+            SecretBox::from_ecdh(pk.as_bytes(), &mut rng, CipherType::Salsa20).unwrap();
+        assert_eq!(crypto_box::beforenm(&ephemeral.into(), &sk), Some(s));
+        assert_eq!(
+            SecretBox::from_ecdh([0u8; 31], &mut rng, CipherType::Salsa20),
+            None
+        );
+        assert_eq!(
+            SecretBox::from_ecdh([0u8; 32], &mut rng, CipherType::Salsa20),
+            None
+        );
+    }
 }
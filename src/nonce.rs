@@ -0,0 +1,332 @@
+//! Deterministic nonce generation
+//!
+//! A nonce must never be used twice with the same key. Instead of drawing random nonces, a sender
+//! can use a `NonceSequence`, which produces nonces of the form `prefix (16 bytes) || counter
+//! (8 bytes, little endian)`.
+//!
+//! `CheckpointedNonce` additionally stores its state in a file. Counter values are reserved in
+//! windows: before a value from a new window is handed out, the end of the window is written to
+//! disk. A process that restarts after a crash continues after the last reserved window, so it
+//! never reuses a nonce, at the cost of skipping the unused values of that window. Only one
+//! `CheckpointedNonce` at a time can use a checkpoint file; it holds an exclusive lock on a
+//! `.lock` file next to it.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Size of the fixed prefix of a nonce
+pub const PREFIX_BYTES: usize = 16;
+
+/// Errors that can occur while generating nonces
+#[derive(Debug)]
+pub enum NonceError {
+    /// All counter values have been used
+    Exhausted,
+    /// The checkpoint could not be read or written
+    Io(io::Error),
+    /// The checkpoint file is corrupted
+    InvalidCheckpoint,
+    /// The checkpoint file is in use by another sequence
+    Locked,
+}
+
+impl fmt::Display for NonceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NonceError::Exhausted => write!(f, "nonce sequence is exhausted"),
+            NonceError::Io(e) => write!(f, "could not access nonce checkpoint: {}", e),
+            NonceError::InvalidCheckpoint => write!(f, "nonce checkpoint is corrupted"),
+            NonceError::Locked => write!(f, "nonce checkpoint is in use"),
+        }
+    }
+}
+
+impl std::error::Error for NonceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NonceError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NonceError {
+    fn from(e: io::Error) -> Self {
+        NonceError::Io(e)
+    }
+}
+
+/// Source of unique nonces
+pub trait NonceSequence {
+    /// Returns the next nonce of the sequence
+    fn next_nonce(&mut self) -> Result<[u8; 24], NonceError>;
+}
+
+/// Builds a nonce out of a prefix and a counter
+pub fn make_nonce(prefix: [u8; PREFIX_BYTES], counter: u64) -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    nonce[..PREFIX_BYTES].copy_from_slice(&prefix);
+    nonce[PREFIX_BYTES..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Splits a nonce into its prefix and counter
+pub fn split_nonce(nonce: [u8; 24]) -> ([u8; PREFIX_BYTES], u64) {
+    let mut prefix = [0u8; PREFIX_BYTES];
+    prefix.copy_from_slice(&nonce[..PREFIX_BYTES]);
+    let mut counter = [0u8; 8];
+    counter.copy_from_slice(&nonce[PREFIX_BYTES..]);
+    (prefix, u64::from_le_bytes(counter))
+}
+
+/// Nonce sequence with an all-zero prefix
+///
+/// Only use this if the key is never used by more than one sender, and the counter never goes
+/// back, for example because the key is only used for a single session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CounterNonce {
+    counter: Option<u64>,
+}
+
+impl CounterNonce {
+    /// Creates a new sequence starting at the given counter value
+    pub fn new(start: u64) -> Self {
+        Self {
+            counter: Some(start),
+        }
+    }
+}
+
+impl NonceSequence for CounterNonce {
+    fn next_nonce(&mut self) -> Result<[u8; 24], NonceError> {
+        let counter = self.counter.ok_or(NonceError::Exhausted)?;
+        self.counter = counter.checked_add(1);
+        Ok(make_nonce([0u8; PREFIX_BYTES], counter))
+    }
+}
+
+/// Nonce sequence with a random prefix and a counter starting at 0
+///
+/// Several senders can share a key, as long as each of them uses its own sequence.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RandomPrefixNonce {
+    prefix: [u8; PREFIX_BYTES],
+    counter: Option<u64>,
+}
+
+impl RandomPrefixNonce {
+    /// Creates a new sequence with a prefix generated by the passed CSPRNG
+    pub fn new<R>(rng: &mut R) -> Self
+    where
+        R: rand::Rng + rand::CryptoRng,
+    {
+        let mut prefix = [0u8; PREFIX_BYTES];
+        rng.fill_bytes(&mut prefix);
+        Self {
+            prefix,
+            counter: Some(0),
+        }
+    }
+    /// Returns the prefix of this sequence
+    pub fn prefix(&self) -> [u8; PREFIX_BYTES] {
+        self.prefix
+    }
+}
+
+impl NonceSequence for RandomPrefixNonce {
+    fn next_nonce(&mut self) -> Result<[u8; 24], NonceError> {
+        let counter = self.counter.ok_or(NonceError::Exhausted)?;
+        self.counter = counter.checked_add(1);
+        Ok(make_nonce(self.prefix, counter))
+    }
+}
+
+/// Nonce sequence that persists its state in a checkpoint file
+///
+/// The file contains the prefix, followed by the first counter value that has not been reserved
+/// yet (8 bytes, little endian).
+#[derive(Debug)]
+pub struct CheckpointedNonce {
+    path: PathBuf,
+    /// Lock file that is held as long as the sequence exists
+    _lock: fs::File,
+    prefix: [u8; PREFIX_BYTES],
+    counter: u64,
+    reserved_until: u64,
+    window: u64,
+}
+
+impl CheckpointedNonce {
+    /// Opens a checkpoint file, or creates it with the given prefix if it does not exist yet.
+    /// A larger window needs fewer writes, but skips more nonces after a restart.
+    ///
+    /// Returns `NonceError::Locked` if another sequence, possibly in another process, has the
+    /// checkpoint open. Panics if the window is 0
+    pub fn open<P>(path: P, prefix: [u8; PREFIX_BYTES], window: u64) -> Result<Self, NonceError>
+    where
+        P: AsRef<Path>,
+    {
+        assert!(window > 0);
+        let path = path.as_ref().to_path_buf();
+        let lock = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(with_suffix(&path, ".lock"))?;
+        lock.try_lock().map_err(|e| match e {
+            fs::TryLockError::WouldBlock => NonceError::Locked,
+            fs::TryLockError::Error(e) => NonceError::Io(e),
+        })?;
+        let (prefix, counter) = match fs::read(&path) {
+            Ok(data) => {
+                if data.len() != 24 {
+                    return Err(NonceError::InvalidCheckpoint);
+                }
+                let mut nonce = [0u8; 24];
+                nonce.copy_from_slice(&data);
+                split_nonce(nonce)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (prefix, 0),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            _lock: lock,
+            prefix,
+            counter,
+            reserved_until: counter,
+            window,
+        })
+    }
+    /// Opens a checkpoint file, or creates it with a random prefix if it does not exist yet
+    pub fn open_random<P, R>(path: P, rng: &mut R, window: u64) -> Result<Self, NonceError>
+    where
+        P: AsRef<Path>,
+        R: rand::Rng + rand::CryptoRng,
+    {
+        let mut prefix = [0u8; PREFIX_BYTES];
+        rng.fill_bytes(&mut prefix);
+        Self::open(path, prefix, window)
+    }
+    /// Returns the prefix of this sequence
+    pub fn prefix(&self) -> [u8; PREFIX_BYTES] {
+        self.prefix
+    }
+    /// Atomically replaces the checkpoint file
+    fn reserve(&mut self, until: u64) -> Result<(), NonceError> {
+        let tmp = with_suffix(&self.path, ".tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            io::Write::write_all(&mut file, &make_nonce(self.prefix, until))?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        // The rename is only durable once the directory entry is on disk
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            fs::File::open(dir)?.sync_all()?;
+        }
+        self.reserved_until = until;
+        Ok(())
+    }
+}
+
+/// Appends a suffix to the file name of a path
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+impl NonceSequence for CheckpointedNonce {
+    fn next_nonce(&mut self) -> Result<[u8; 24], NonceError> {
+        if self.counter == u64::MAX {
+            return Err(NonceError::Exhausted);
+        }
+        if self.counter >= self.reserved_until {
+            let until = self.counter.saturating_add(self.window);
+            self.reserve(until)?;
+        }
+        let nonce = make_nonce(self.prefix, self.counter);
+        self.counter += 1;
+        Ok(nonce)
+    }
+}
+
+impl crate::SecretBox {
+    /// Seals a message using the next nonce of a sequence. Like `easy_seal`, the nonce is
+    /// prepended to the box, so it can be opened with `easy_unseal`.
+    pub fn seal_next<N>(&self, nonces: &mut N, message: &[u8]) -> Result<Vec<u8>, NonceError>
+    where
+        N: NonceSequence + ?Sized,
+    {
+        let nonce = nonces.next_nonce()?;
+        let mut v = Vec::with_capacity(message.len() + 16 + 24);
+        v.extend_from_slice(&nonce);
+        v.extend_from_slice(&self.seal(message, nonce));
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CipherType, SecretBox};
+
+    #[test]
+    fn counter() {
+        let mut seq = CounterNonce::new(u64::MAX - 1);
+        assert_eq!(seq.next_nonce().unwrap(), make_nonce([0; 16], u64::MAX - 1));
+        assert_eq!(seq.next_nonce().unwrap(), make_nonce([0; 16], u64::MAX));
+        assert!(seq.next_nonce().is_err());
+    }
+
+    #[test]
+    fn random_prefix() {
+        let mut seq = RandomPrefixNonce::new(&mut rand::rngs::OsRng);
+        let (prefix, counter) = split_nonce(seq.next_nonce().unwrap());
+        assert_eq!((prefix, counter), (seq.prefix(), 0));
+        assert_eq!(split_nonce(seq.next_nonce().unwrap()).1, 1);
+
+        let s = SecretBox::new([9u8; 32], CipherType::Salsa20).unwrap();
+        let sealed = s.seal_next(&mut seq, b"hello").unwrap();
+        assert_eq!(&sealed[..24], &make_nonce(seq.prefix(), 2)[..]);
+        assert_eq!(s.easy_unseal(&sealed).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn checkpoint() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("secretbox-nonce-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut seq = CheckpointedNonce::open_random(&path, &mut rand::rngs::OsRng, 10).unwrap();
+        match CheckpointedNonce::open(&path, [0; 16], 10) {
+            Err(NonceError::Locked) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let prefix = seq.prefix();
+        for i in 0..15 {
+            assert_eq!(seq.next_nonce().unwrap(), make_nonce(prefix, i));
+        }
+        drop(seq);
+        // The second window was reserved, so a restart continues after it
+        let mut seq = CheckpointedNonce::open(&path, [0; 16], 10).unwrap();
+        assert_eq!(seq.prefix(), prefix);
+        assert_eq!(seq.next_nonce().unwrap(), make_nonce(prefix, 20));
+        drop(seq);
+
+        fs::write(&path, b"short").unwrap();
+        match CheckpointedNonce::open(&path, [0; 16], 10) {
+            Err(NonceError::InvalidCheckpoint) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(with_suffix(&path, ".lock")).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Padding that hides the exact length of a message
+//!
+//! The plaintext is padded before it is sealed, so the padding is encrypted and authenticated
+//! along with it. The padding follows ISO/IEC 7816-4, like libsodium's `sodium_pad`: a `0x80`
+//! byte followed by as many zeroes as needed. At least one byte of padding is always added.
+//!
+//! The padded length is chosen by a `Padding` scheme:
+//!
+//! - `Block(n)` pads to the next multiple of `n` bytes, which hides the length within a block.
+//! - `Padme` pads to a length whose binary representation ends in zeroes, as described in
+//!   "Reducing Metadata Leakage from Encrypted Files and Communication with PURBs". It leaks at
+//!   most O(log log n) bits of the length, with an overhead of at most 12%.
+//!
+//! `unpad` takes time that depends only on the padded length, not on where the padding starts.
+use crate::SecretBox;
+
+/// Padding scheme
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Padding {
+    /// Pads to the next length in the PADME sequence
+    Padme,
+    /// Pads to the next multiple of the block size
+    Block(usize),
+}
+
+impl Padding {
+    /// Returns the padded length of a message of the given length, including the `0x80` marker
+    ///
+    /// Returns None if the block size is 0 or the length overflows
+    pub fn padded_len(self, len: usize) -> Option<usize> {
+        let len = len.checked_add(1)?;
+        match self {
+            Padding::Block(0) => None,
+            Padding::Block(n) => len.checked_add(n - 1).map(|l| l / n * n),
+            Padding::Padme => {
+                if len < 2 {
+                    return Some(len);
+                }
+                let e = (0usize.leading_zeros() - 1 - len.leading_zeros()) as usize;
+                let s = (0usize.leading_zeros() - e.leading_zeros()) as usize;
+                let mask = (1usize << (e - s)) - 1;
+                len.checked_add(mask).map(|l| l & !mask)
+            }
+        }
+    }
+}
+
+/// Appends padding to a message
+///
+/// Returns None if the block size is 0
+pub fn pad(message: &mut Vec<u8>, padding: Padding) -> Option<()> {
+    let padded_len = padding.padded_len(message.len())?;
+    message.push(0x80);
+    message.resize(padded_len, 0);
+    Some(())
+}
+
+/// Returns 1 if the byte is zero and 0 otherwise, without branches
+#[inline(always)]
+fn is_zero(b: u8) -> usize {
+    ((b as usize).wrapping_sub(1) >> (0usize.leading_zeros() - 1)) & 1
+}
+
+/// Returns the length of a padded message without the padding
+///
+/// The whole message is always scanned, so the time taken does not depend on the length of the
+/// padding. Returns None if the padding is malformed or does not match the padding scheme.
+pub fn unpad(padded: &[u8], padding: Padding) -> Option<usize> {
+    let mut found = 0usize;
+    let mut invalid = 0usize;
+    let mut len = 0usize;
+    for (i, &b) in padded.iter().enumerate().rev() {
+        let zero = is_zero(b);
+        let marker = is_zero(b ^ 0x80);
+        let hit = !found & marker & 1;
+        // Bytes after the marker must all be zero
+        invalid |= !found & !zero & !marker & 1;
+        let mask = hit.wrapping_neg();
+        len = (i & mask) | (len & !mask);
+        found |= hit;
+    }
+    if found & !invalid == 0 || padding.padded_len(len)? != padded.len() {
+        return None;
+    }
+    Some(len)
+}
+
+impl SecretBox {
+    /// Pads a message and seals it
+    ///
+    /// Returns None if the block size is 0
+    pub fn seal_padded(
+        &self,
+        message: &[u8],
+        nonce: [u8; 24],
+        padding: Padding,
+    ) -> Option<Vec<u8>> {
+        let padded_len = padding.padded_len(message.len())?;
+        let mut padded = Vec::with_capacity(padded_len);
+        padded.extend_from_slice(message);
+        pad(&mut padded, padding)?;
+        Some(self.seal(&padded, nonce))
+    }
+
+    /// Opens a box created by `seal_padded` and removes the padding
+    ///
+    /// Returns None if the box is not authentic or the padding is malformed
+    pub fn open_padded(&self, data: &[u8], nonce: [u8; 24], padding: Padding) -> Option<Vec<u8>> {
+        let mut message = self.unseal(data, nonce)?;
+        let len = unpad(&message, padding)?;
+        message.truncate(len);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CipherType;
+
+    #[test]
+    fn padded_len() {
+        let padme: Vec<usize> = [0, 1, 2, 8, 9, 10, 15, 16, 17, 100, 1000]
+            .iter()
+            .map(|&l| Padding::Padme.padded_len(l).unwrap())
+            .collect();
+        assert_eq!(padme, vec![1, 2, 3, 10, 10, 12, 16, 18, 18, 104, 1024]);
+        assert_eq!(Padding::Block(16).padded_len(0), Some(16));
+        assert_eq!(Padding::Block(16).padded_len(15), Some(16));
+        assert_eq!(Padding::Block(16).padded_len(16), Some(32));
+        assert_eq!(Padding::Block(0).padded_len(16), None);
+        for l in 0..5000 {
+            let p = Padding::Padme.padded_len(l).unwrap();
+            assert!(p > l && p - l - 1 <= l / 8 + 1);
+        }
+    }
+
+    #[test]
+    fn unpadding() {
+        for &padding in [Padding::Padme, Padding::Block(1), Padding::Block(64)].iter() {
+            for len in 0..200 {
+                let mut m = vec![0x80u8; len];
+                pad(&mut m, padding).unwrap();
+                assert_eq!(m.len(), padding.padded_len(len).unwrap());
+                assert_eq!(unpad(&m, padding), Some(len));
+            }
+        }
+        let block = Padding::Block(4);
+        assert_eq!(unpad(&[1, 2, 0x80, 0], block), Some(2));
+        assert_eq!(unpad(&[1, 2, 0x80, 1], block), None);
+        assert_eq!(unpad(&[1, 2, 3, 0], block), None);
+        assert_eq!(unpad(&[0, 0, 0, 0], block), None);
+        assert_eq!(unpad(&[], block), None);
+        // Valid padding, but not the length the scheme would produce
+        assert_eq!(unpad(&[0x80, 0, 0, 0, 0, 0, 0, 0], block), None);
+    }
+
+    #[test]
+    fn seal_padded() {
+        let s = SecretBox::new([3u8; 32], CipherType::Chacha20).unwrap();
+        let a = s.seal_padded(b"ls", [1u8; 24], Padding::Block(32)).unwrap();
+        let b = s
+            .seal_padded(b"rm -rf /", [1u8; 24], Padding::Block(32))
+            .unwrap();
+        assert_eq!(a.len(), b.len());
+        assert_eq!(
+            s.open_padded(&b, [1u8; 24], Padding::Block(32)).unwrap(),
+            b"rm -rf /".to_vec()
+        );
+        assert_eq!(s.open_padded(&b, [1u8; 24], Padding::Block(16)), None);
+        assert_eq!(s.seal_padded(b"ls", [1u8; 24], Padding::Block(0)), None);
+        // Boxes without padding are rejected
+        let unpadded = s.seal(&[0u8; 32], [1u8; 24]);
+        assert_eq!(
+            s.open_padded(&unpadded, [1u8; 24], Padding::Block(32)),
+            None
+        );
+    }
+}
@@ -2,6 +2,8 @@
 use crate::U256;
 use std::cmp::min;
 
+pub mod vector;
+
 /// This function clamps r to be in the correct format
 fn clamp(input: u128) -> u128 {
     input & 0xffffffc0ffffffc0ffffffc0fffffff
@@ -14,6 +16,9 @@ pub struct Poly1305 {
     acc: U256,
     r: u128,
     s: u128,
+    /// Powers of r for the vectorized implementation, computed when the first 64 bytes are
+    /// absorbed. The inner value is None if the vectorized implementation is not available.
+    powers: Option<Option<vector::Powers>>,
 }
 
 impl Poly1305 {
@@ -22,6 +27,7 @@ impl Poly1305 {
             acc: U256::zero(),
             r: clamp(r),
             s,
+            powers: None,
         }
     }
     /// Reads one block. Panics if the size is larger than 16
@@ -47,11 +53,28 @@ impl Poly1305 {
     pub fn finalize(&self) -> u128 {
         (self.acc + U256::from(self.s)).low_u128()
     }
-    /// Hashes a message, one block at a time, then finalizes the output
-    pub fn hash(&mut self, data: &[u8]) -> u128 {
+    /// Absorbs part of a message. Every call except the last must pass a multiple of 16 bytes,
+    /// otherwise the tag is wrong; callers outside this crate use `hash`.
+    ///
+    /// If the CPU supports AVX2, groups of four blocks are processed at once by the `vector`
+    /// implementation. The remaining blocks are processed one at a time.
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        let mut data = data;
+        if data.len() >= 64 {
+            let r = self.r;
+            if let Some(powers) = self.powers.get_or_insert_with(|| vector::Powers::new(r)) {
+                let (acc, len) = vector::update(self.acc, powers, data);
+                self.acc = acc;
+                data = &data[len..];
+            }
+        }
         for i in 0..((data.len() + 15) / 16) {
             self.read_block(&data[(i * 16)..min((i + 1) * 16, data.len())]);
         }
+    }
+    /// Hashes a message, then finalizes the output
+    pub fn hash(&mut self, data: &[u8]) -> u128 {
+        self.update(data);
         self.finalize()
     }
     /// Verifies a message based on a certain hash
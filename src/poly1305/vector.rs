@@ -0,0 +1,284 @@
+//! Four-way Poly1305
+//!
+//! Poly1305 computes `h = m_1 * r^n + m_2 * r^(n-1) + ... + m_n * r` modulo 2^130 - 5. Splitting
+//! the blocks into four interleaved streams, every stream can be evaluated on its own with r^4 in
+//! place of r, and the streams are combined at the end by multiplying them with r^4, r^3, r^2 and
+//! r. The four streams are independent, so they are computed side by side in radix 2^26, which
+//! maps onto the 32x32->64 bit multiplications of AVX2. The code is plain Rust; when AVX2 is
+//! detected at runtime, it is compiled with AVX2 enabled so that the lanes are vectorized.
+use crate::U256;
+
+const MASK: u64 = 0x3ff_ffff;
+
+/// Number in radix 2^26
+type Limbs = [u64; 5];
+
+/// Returns whether the vectorized implementation can be used on this CPU
+pub fn available() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        is_x86_feature_detected!("avx2")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
+
+/// Converts a 16-byte block into limbs, with the 2^128 bit set
+#[inline(always)]
+fn block(b: &[u8]) -> Limbs {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(b);
+    let x = u128::from_le_bytes(bytes);
+    [
+        x as u64 & MASK,
+        (x >> 26) as u64 & MASK,
+        (x >> 52) as u64 & MASK,
+        (x >> 78) as u64 & MASK,
+        (x >> 104) as u64 | (1 << 24),
+    ]
+}
+
+fn from_u256(x: U256) -> Limbs {
+    let mut limbs = [0u64; 5];
+    for (i, l) in limbs.iter_mut().enumerate() {
+        *l = (x >> (26 * i)).low_u64() & MASK;
+    }
+    limbs
+}
+
+fn to_u256(h: Limbs) -> U256 {
+    h.iter().enumerate().fold(U256::zero(), |acc, (i, l)| {
+        acc + (U256::from(*l) << (26 * i))
+    })
+}
+
+/// Propagates carries so that every limb fits into 26 bits, except for a possible small excess
+/// in the lowest two limbs
+#[inline(always)]
+fn carry(mut d: Limbs) -> Limbs {
+    let mut c;
+    c = d[0] >> 26;
+    d[0] &= MASK;
+    d[1] += c;
+    c = d[1] >> 26;
+    d[1] &= MASK;
+    d[2] += c;
+    c = d[2] >> 26;
+    d[2] &= MASK;
+    d[3] += c;
+    c = d[3] >> 26;
+    d[3] &= MASK;
+    d[4] += c;
+    c = d[4] >> 26;
+    d[4] &= MASK;
+    d[0] += c * 5;
+    c = d[0] >> 26;
+    d[0] &= MASK;
+    d[1] += c;
+    d
+}
+
+/// Multiplies two numbers modulo 2^130 - 5
+#[inline(always)]
+fn mul(h: &Limbs, r: &Limbs) -> Limbs {
+    let s = [r[0], r[1] * 5, r[2] * 5, r[3] * 5, r[4] * 5];
+    carry([
+        h[0] * r[0] + h[1] * s[4] + h[2] * s[3] + h[3] * s[2] + h[4] * s[1],
+        h[0] * r[1] + h[1] * r[0] + h[2] * s[4] + h[3] * s[3] + h[4] * s[2],
+        h[0] * r[2] + h[1] * r[1] + h[2] * r[0] + h[3] * s[4] + h[4] * s[3],
+        h[0] * r[3] + h[1] * r[2] + h[2] * r[1] + h[3] * r[0] + h[4] * s[4],
+        h[0] * r[4] + h[1] * r[3] + h[2] * r[2] + h[3] * r[1] + h[4] * r[0],
+    ])
+}
+
+/// Fully reduces a number modulo 2^130 - 5, without branches
+fn reduce(h: Limbs) -> Limbs {
+    let h = carry(carry(h));
+    // g = h + 5 - 2^130, which is the result if h >= 2^130 - 5
+    let mut g = [0u64; 5];
+    let mut c = 5;
+    for (g, h) in g.iter_mut().zip(h.iter()) {
+        *g = h + c;
+        c = *g >> 26;
+        *g &= MASK;
+    }
+    let use_g = (c & 1).wrapping_neg();
+    let mut out = [0u64; 5];
+    for (o, (g, h)) in out.iter_mut().zip(g.iter().zip(h.iter())) {
+        *o = (g & use_g) | (h & !use_g);
+    }
+    out
+}
+
+/// Powers r, r^2, r^3 and r^4 of the key
+///
+/// They are only computed if the vectorized implementation is available, so holding a `Powers`
+/// means that `update` can use AVX2.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Powers([Limbs; 4]);
+
+impl Powers {
+    /// Computes the powers of the clamped key `r`
+    ///
+    /// Returns None if the vectorized implementation cannot be used on this CPU
+    pub fn new(r: u128) -> Option<Self> {
+        if !available() {
+            return None;
+        }
+        Some(Self::compute(r))
+    }
+    fn compute(r: u128) -> Self {
+        let r1 = from_u256(U256::from(r));
+        let r2 = reduce(mul(&r1, &r1));
+        let r3 = reduce(mul(&r2, &r1));
+        let r4 = reduce(mul(&r3, &r1));
+        Powers([r1, r2, r3, r4])
+    }
+}
+
+/// Processes blocks in groups of four. `acc` is the accumulator before the first block. Only
+/// complete groups of 64 bytes are processed; the number of bytes read is returned together with
+/// the new accumulator.
+#[inline(always)]
+fn update_lanes(acc: U256, powers: &Powers, data: &[u8]) -> (U256, usize) {
+    let [r1, r2, r3, r4] = powers.0;
+    let s4 = [r4[0], r4[1] * 5, r4[2] * 5, r4[3] * 5, r4[4] * 5];
+
+    // Limb-major state: h[limb][lane]
+    let mut h = [[0u64; 4]; 5];
+    let start = from_u256(acc);
+    for (limb, s) in h.iter_mut().zip(start.iter()) {
+        limb[0] = *s;
+    }
+    let groups = data.chunks_exact(64);
+    let len = data.len() - groups.remainder().len();
+    let mut first = true;
+    for group in groups {
+        let mut m = [[0u64; 4]; 5];
+        for (lane, b) in group.chunks_exact(16).enumerate() {
+            for (limb, x) in m.iter_mut().zip(block(b).iter()) {
+                limb[lane] = *x;
+            }
+        }
+        if first {
+            // The accumulator is added to the first block of the first lane
+            for (limb, x) in h.iter_mut().zip(m.iter()) {
+                for (a, b) in limb.iter_mut().zip(x.iter()) {
+                    *a += b;
+                }
+            }
+            first = false;
+            continue;
+        }
+        // h = h * r^4 + m, for all lanes
+        let mut d = [[0u64; 4]; 5];
+        for lane in 0..4 {
+            let (h0, h1, h2) = (h[0][lane], h[1][lane], h[2][lane]);
+            let (h3, h4) = (h[3][lane], h[4][lane]);
+            d[0][lane] = h0 * r4[0] + h1 * s4[4] + h2 * s4[3] + h3 * s4[2] + h4 * s4[1];
+            d[1][lane] = h0 * r4[1] + h1 * r4[0] + h2 * s4[4] + h3 * s4[3] + h4 * s4[2];
+            d[2][lane] = h0 * r4[2] + h1 * r4[1] + h2 * r4[0] + h3 * s4[4] + h4 * s4[3];
+            d[3][lane] = h0 * r4[3] + h1 * r4[2] + h2 * r4[1] + h3 * r4[0] + h4 * s4[4];
+            d[4][lane] = h0 * r4[4] + h1 * r4[3] + h2 * r4[2] + h3 * r4[1] + h4 * r4[0];
+        }
+        for lane in 0..4 {
+            let c = carry([d[0][lane], d[1][lane], d[2][lane], d[3][lane], d[4][lane]]);
+            for (limb, (x, m)) in h.iter_mut().zip(c.iter().zip(m.iter())) {
+                limb[lane] = x + m[lane];
+            }
+        }
+    }
+    if first {
+        return (acc, 0);
+    }
+
+    // Combine the lanes: h_0 * r^4 + h_1 * r^3 + h_2 * r^2 + h_3 * r
+    let powers = [r4, r3, r2, r1];
+    let mut sum = [0u64; 5];
+    for (lane, power) in powers.iter().enumerate() {
+        let lane_h = [h[0][lane], h[1][lane], h[2][lane], h[3][lane], h[4][lane]];
+        for (s, x) in sum.iter_mut().zip(mul(&carry(lane_h), power).iter()) {
+            *s += x;
+        }
+    }
+    (to_u256(reduce(sum)), len)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn update_avx2(acc: U256, powers: &Powers, data: &[u8]) -> (U256, usize) {
+    update_lanes(acc, powers, data)
+}
+
+/// Processes as many groups of four blocks as possible using AVX2
+pub fn update(acc: U256, powers: &Powers, data: &[u8]) -> (U256, usize) {
+    // Safe because `Powers::new` checked that the CPU supports AVX2
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    return unsafe { update_avx2(acc, powers, data) };
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    update_lanes(acc, powers, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::poly1305::{Poly1305, PRIME};
+    use rand::RngCore;
+
+    #[test]
+    fn reduction() {
+        let p = from_u256(PRIME);
+        assert_eq!(reduce(p), [0; 5]);
+        let mut p_plus_one = p;
+        p_plus_one[0] += 1;
+        assert_eq!(reduce(p_plus_one), [1, 0, 0, 0, 0]);
+        let below = from_u256(PRIME - U256::from(1));
+        assert_eq!(reduce(below), below);
+    }
+
+    #[test]
+    fn differential() {
+        let mut rng = rand::rngs::OsRng;
+        for _ in 0..200 {
+            let mut key = [0u8; 32];
+            rng.fill_bytes(&mut key);
+            let r = u128::from_le_bytes([
+                key[0], key[1], key[2], key[3], key[4], key[5], key[6], key[7], key[8], key[9],
+                key[10], key[11], key[12], key[13], key[14], key[15],
+            ]);
+            let s = u128::from_le_bytes([
+                key[16], key[17], key[18], key[19], key[20], key[21], key[22], key[23], key[24],
+                key[25], key[26], key[27], key[28], key[29], key[30], key[31],
+            ]);
+            let mut data = vec![0u8; 16 + (rng.next_u32() % 600) as usize];
+            rng.fill_bytes(&mut data);
+            if rng.next_u32() & 3 == 0 {
+                // Blocks of all ones exercise the carries
+                for b in data.iter_mut() {
+                    *b = 0xff;
+                }
+            }
+
+            let mut scalar = Poly1305::new(r, s);
+            for chunk in data.chunks(16) {
+                scalar.read_block(chunk);
+            }
+
+            // Starting from an arbitrary accumulator, lanes and scalar code agree
+            let mut lanes = Poly1305::new(r, s);
+            let mut prefix = Poly1305::new(r, s);
+            prefix.read_block(&data[..16]);
+            let rest = &data[16..];
+            let (acc, len) = update_lanes(prefix.acc, &Powers::compute(lanes.r), rest);
+            assert_eq!(len, rest.len() / 64 * 64);
+            lanes.acc = acc;
+            for chunk in rest[len..].chunks(16) {
+                lanes.read_block(chunk);
+            }
+            assert_eq!(lanes.finalize(), scalar.finalize());
+            assert_eq!(Poly1305::new(r, s).hash(&data), scalar.finalize());
+        }
+    }
+}
@@ -0,0 +1,422 @@
+//! Envelope encryption with data keys from a key provider
+//!
+//! A `KeyProvider` manages key-encryption keys, which are identified by key IDs and never leave
+//! the provider. For every message, the provider generates a fresh data key and returns it
+//! together with a wrapped copy. The message is sealed with the data key, and the wrapped data key
+//! is stored next to the box in a `KeyEnvelope`. To open the envelope, the provider is asked to
+//! unwrap the data key.
+//!
+//! `FileKeyProvider` is a reference implementation that keeps its key-encryption keys in a
+//! directory and wraps data keys with `SecretBox::wrap_key`. Other backends, such as a remote key
+//! management service, can be added by implementing `KeyProvider`.
+//!
+//! The serialized form of a `KeyEnvelope` is `length of the key ID (1 byte) || key ID || length of
+//! the wrapped key (2 bytes, big endian) || wrapped key || envelope`, where the envelope has the
+//! format of the `envelope` module.
+use crate::envelope::{self, EnvelopeError};
+use crate::wrap::{Key, WrapError, WrappedKey};
+use crate::{CipherType, SecretBox};
+use rand::RngCore;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Errors that can occur while using a key provider
+#[derive(Debug)]
+pub enum ProviderError {
+    /// The key ID is not known to the provider
+    UnknownKeyId(String),
+    /// The key ID cannot be used with this provider
+    InvalidKeyId(String),
+    /// A key already exists with the given key ID
+    KeyExists(String),
+    /// A wrapped data key could not be unwrapped
+    Wrap(WrapError),
+    /// The envelope is malformed or not authentic
+    Envelope(EnvelopeError),
+    /// The keys of the provider could not be read or written
+    Io(io::Error),
+    /// Error reported by another backend
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProviderError::UnknownKeyId(id) => write!(f, "unknown key ID {:?}", id),
+            ProviderError::InvalidKeyId(id) => write!(f, "invalid key ID {:?}", id),
+            ProviderError::KeyExists(id) => write!(f, "key ID {:?} already exists", id),
+            ProviderError::Wrap(e) => write!(f, "could not unwrap data key: {}", e),
+            ProviderError::Envelope(e) => write!(f, "could not open envelope: {}", e),
+            ProviderError::Io(e) => write!(f, "could not access keys: {}", e),
+            ProviderError::Backend(e) => write!(f, "key provider failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProviderError::Wrap(e) => Some(e),
+            ProviderError::Envelope(e) => Some(e),
+            ProviderError::Io(e) => Some(e),
+            ProviderError::Backend(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProviderError {
+    fn from(e: io::Error) -> Self {
+        ProviderError::Io(e)
+    }
+}
+
+impl From<WrapError> for ProviderError {
+    fn from(e: WrapError) -> Self {
+        ProviderError::Wrap(e)
+    }
+}
+
+impl From<EnvelopeError> for ProviderError {
+    fn from(e: EnvelopeError) -> Self {
+        ProviderError::Envelope(e)
+    }
+}
+
+/// Data key generated by a key provider
+#[derive(Clone, Eq, PartialEq)]
+pub struct DataKey {
+    /// Plaintext data key, used to seal the message
+    pub key: Key,
+    /// Data key wrapped under the key-encryption key, stored with the message
+    pub wrapped: Vec<u8>,
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DataKey")
+            .field("key", &"<redacted>")
+            .field("wrapped", &self.wrapped)
+            .finish()
+    }
+}
+
+/// Source of data keys
+pub trait KeyProvider {
+    /// Generates a new data key, wrapped under the key-encryption key with the given ID
+    fn generate_data_key(&self, key_id: &str) -> Result<DataKey, ProviderError>;
+    /// Unwraps a data key that was generated with the given key ID
+    fn decrypt_data_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Key, ProviderError>;
+    /// Returns the IDs of all key-encryption keys of the provider
+    fn key_ids(&self) -> Result<Vec<String>, ProviderError>;
+}
+
+/// Sealed message together with its wrapped data key
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyEnvelope {
+    /// ID of the key-encryption key
+    pub key_id: String,
+    /// Wrapped data key, as returned by the provider
+    pub wrapped_key: Vec<u8>,
+    /// Message sealed with the data key, in the format of the `envelope` module
+    pub envelope: Vec<u8>,
+}
+
+impl KeyEnvelope {
+    /// Seals a message with a new data key from the provider
+    ///
+    /// Returns an error if the provider fails, or if the key ID or wrapped key are too long to be
+    /// serialized
+    pub fn seal<P>(
+        provider: &P,
+        key_id: &str,
+        message: &[u8],
+        cipher: CipherType,
+    ) -> Result<Self, ProviderError>
+    where
+        P: KeyProvider + ?Sized,
+    {
+        if key_id.len() > u8::MAX as usize {
+            return Err(ProviderError::InvalidKeyId(key_id.to_string()));
+        }
+        let data_key = provider.generate_data_key(key_id)?;
+        if data_key.wrapped.len() > u16::MAX as usize {
+            return Err(ProviderError::Wrap(WrapError::InvalidLength(
+                data_key.wrapped.len(),
+            )));
+        }
+        let envelope = SecretBox {
+            key: data_key.key,
+            cipher,
+        }
+        .seal_envelope(message);
+        Ok(Self {
+            key_id: key_id.to_string(),
+            wrapped_key: data_key.wrapped,
+            envelope,
+        })
+    }
+    /// Opens the envelope with the data key unwrapped by the provider
+    pub fn open<P>(&self, provider: &P) -> Result<Vec<u8>, ProviderError>
+    where
+        P: KeyProvider + ?Sized,
+    {
+        let key = provider.decrypt_data_key(&self.key_id, &self.wrapped_key)?;
+        Ok(envelope::open_with_key(key, &self.envelope)?)
+    }
+    /// Parses a serialized envelope
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProviderError> {
+        let truncated = || ProviderError::Envelope(EnvelopeError::Truncated);
+        let id_len = *data.first().ok_or_else(truncated)? as usize;
+        let rest = &data[1..];
+        if rest.len() < id_len + 2 {
+            return Err(truncated());
+        }
+        let key_id = String::from_utf8(rest[..id_len].to_vec()).map_err(|e| {
+            ProviderError::InvalidKeyId(String::from_utf8_lossy(e.as_bytes()).into_owned())
+        })?;
+        let rest = &rest[id_len..];
+        let wrapped_len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        let rest = &rest[2..];
+        if rest.len() < wrapped_len {
+            return Err(truncated());
+        }
+        Ok(Self {
+            key_id,
+            wrapped_key: rest[..wrapped_len].to_vec(),
+            envelope: rest[wrapped_len..].to_vec(),
+        })
+    }
+    /// Serializes the envelope
+    ///
+    /// Panics if the key ID is longer than 255 bytes or the wrapped key is longer than 65535
+    /// bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!(self.key_id.len() <= u8::MAX as usize);
+        assert!(self.wrapped_key.len() <= u16::MAX as usize);
+        let mut out = Vec::with_capacity(
+            1 + self.key_id.len() + 2 + self.wrapped_key.len() + self.envelope.len(),
+        );
+        out.push(self.key_id.len() as u8);
+        out.extend_from_slice(self.key_id.as_bytes());
+        out.extend_from_slice(&(self.wrapped_key.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.wrapped_key);
+        out.extend_from_slice(&self.envelope);
+        out
+    }
+}
+
+/// Key provider that stores its key-encryption keys in a directory
+///
+/// Every key is stored as 32 raw bytes in a file named `<key ID>.key`, which on unix is only
+/// readable by its owner. Key IDs may only contain ASCII letters, digits, `-` and `_`. Data keys
+/// are wrapped with `SecretBox::wrap_key`, using the given cipher.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileKeyProvider {
+    dir: PathBuf,
+    cipher: CipherType,
+}
+
+impl FileKeyProvider {
+    /// Creates a provider for the keys in a directory. The directory must exist.
+    pub fn new<P>(dir: P, cipher: CipherType) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            cipher,
+        }
+    }
+    /// Returns the path of the key file with the given ID
+    fn path(&self, key_id: &str) -> Result<PathBuf, ProviderError> {
+        let valid = key_id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if key_id.is_empty() || key_id.len() > u8::MAX as usize || !valid {
+            return Err(ProviderError::InvalidKeyId(key_id.to_string()));
+        }
+        Ok(self.dir.join(format!("{}.key", key_id)))
+    }
+    /// Reads the key-encryption key with the given ID
+    fn kek(&self, key_id: &str) -> Result<SecretBox, ProviderError> {
+        let key = match fs::read(self.path(key_id)?) {
+            Ok(key) => key,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(ProviderError::UnknownKeyId(key_id.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if key.len() != 32 {
+            return Err(ProviderError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "key file does not contain a 32-byte key",
+            )));
+        }
+        Ok(SecretBox::new(&key, self.cipher).unwrap())
+    }
+    /// Creates a new random key-encryption key
+    ///
+    /// Returns an error if a key with this ID already exists. The key is written to a temporary
+    /// file first and linked into place once complete, so a failed write leaves no key file.
+    pub fn create_key(&self, key_id: &str) -> Result<(), ProviderError> {
+        let path = self.path(key_id)?;
+        let mut key = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        let tmp = self.dir.join(format!(
+            ".{}.key.{:016x}.tmp",
+            key_id,
+            rand::rngs::OsRng.next_u64()
+        ));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let result = options.open(&tmp).and_then(|mut file| {
+            io::Write::write_all(&mut file, &key)?;
+            file.sync_all()?;
+            // Unlike a rename, linking fails instead of replacing an existing key
+            fs::hard_link(&tmp, &path)
+        });
+        let _ = fs::remove_file(&tmp);
+        match result {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Err(ProviderError::KeyExists(key_id.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl KeyProvider for FileKeyProvider {
+    fn generate_data_key(&self, key_id: &str) -> Result<DataKey, ProviderError> {
+        let kek = self.kek(key_id)?;
+        let mut key = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        Ok(DataKey {
+            key,
+            wrapped: kek.wrap_key(&key).to_bytes().to_vec(),
+        })
+    }
+    fn decrypt_data_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Key, ProviderError> {
+        let kek = self.kek(key_id)?;
+        Ok(kek.unwrap_key(&WrappedKey::from_bytes(wrapped)?)?)
+    }
+    fn key_ids(&self) -> Result<Vec<String>, ProviderError> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let id = match name.to_str().and_then(|n| n.strip_suffix(".key")) {
+                Some(id) => id,
+                None => continue,
+            };
+            if self.path(id).is_ok() {
+                ids.push(id.to_string());
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("secretbox-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn file_provider() {
+        let dir = temp_dir("provider");
+        let provider = FileKeyProvider::new(&dir, CipherType::Chacha20);
+        provider.create_key("billing").unwrap();
+        provider.create_key("audit-2024").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = fs::metadata(dir.join("billing.key")).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        match provider.create_key("billing") {
+            Err(ProviderError::KeyExists(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        match provider.create_key("../escape") {
+            Err(ProviderError::InvalidKeyId(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::write(dir.join("notes.txt"), b"not a key").unwrap();
+        assert_eq!(
+            provider.key_ids().unwrap(),
+            vec!["audit-2024".to_string(), "billing".to_string()]
+        );
+
+        let data_key = provider.generate_data_key("billing").unwrap();
+        let debug = format!("{:?}", data_key);
+        assert!(debug.contains("<redacted>"));
+        assert!(!debug.contains(&format!("{:?}", data_key.key)));
+        assert_eq!(
+            provider
+                .decrypt_data_key("billing", &data_key.wrapped)
+                .unwrap(),
+            data_key.key
+        );
+        match provider.decrypt_data_key("audit-2024", &data_key.wrapped) {
+            Err(ProviderError::Wrap(WrapError::Unauthentic)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match provider.generate_data_key("missing") {
+            Err(ProviderError::UnknownKeyId(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn envelope() {
+        let dir = temp_dir("provider-envelope");
+        let provider = FileKeyProvider::new(&dir, CipherType::Salsa20);
+        provider.create_key("billing").unwrap();
+        let sealed =
+            KeyEnvelope::seal(&provider, "billing", b"invoice 42", CipherType::Chacha20).unwrap();
+        let bytes = sealed.to_bytes();
+        let parsed = KeyEnvelope::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed, sealed);
+        assert_eq!(parsed.open(&provider).unwrap(), b"invoice 42".to_vec());
+
+        for len in 0..1 + 7 + 2 + 57 {
+            assert!(KeyEnvelope::from_bytes(&bytes[..len]).is_err());
+        }
+        let mut invalid = bytes.clone();
+        invalid[1] = 0xff;
+        match KeyEnvelope::from_bytes(&invalid) {
+            Err(ProviderError::InvalidKeyId(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let mut forged = bytes;
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        match KeyEnvelope::from_bytes(&forged).unwrap().open(&provider) {
+            Err(ProviderError::Envelope(EnvelopeError::Unauthentic)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // A provider without the key cannot open the envelope
+        let other = FileKeyProvider::new(temp_dir("provider-other"), CipherType::Salsa20);
+        other.create_key("billing").unwrap();
+        assert!(sealed.open(&other).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&other.dir).unwrap();
+    }
+}
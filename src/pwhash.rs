@@ -0,0 +1,570 @@
+//! Argon2id password hashing compatible with libsodium's `crypto_pwhash`
+//!
+//! Argon2id (RFC 9106) derives keys from passphrases. Like libsodium, this module always uses a
+//! single lane and version 1.3 of the algorithm. Keys can either be derived directly (for use with
+//! `SecretBox::new`), or a self-describing `$argon2id$` string can be produced that stores the
+//! parameters and the salt alongside the hash.
+use crate::blake2b::Blake2b;
+
+/// Size of the salt in bytes
+pub const SALT_BYTES: usize = 16;
+
+const VERSION: u32 = 0x13;
+const ARGON2ID: u32 = 2;
+const BLOCK_WORDS: usize = 128;
+const SYNC_POINTS: usize = 4;
+/// Largest number of lanes allowed by RFC 9106
+const MAX_LANES: u32 = (1 << 24) - 1;
+
+type Block = [u64; BLOCK_WORDS];
+
+/// Argon2id cost parameters
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Params {
+    ops_limit: u32,
+    mem_limit_kib: u32,
+}
+
+impl Params {
+    /// Creates a new set of parameters. `ops_limit` is the number of passes over the memory and
+    /// `mem_limit` the amount of memory to use in bytes, rounded down to whole KiB.
+    ///
+    /// Returns None if the parameters are below the minimums libsodium accepts (1 pass and 8 KiB)
+    pub fn new(ops_limit: u32, mem_limit: usize) -> Option<Self> {
+        let mem_limit_kib = mem_limit / 1024;
+        if ops_limit < 1 || mem_limit_kib < 8 || mem_limit_kib > u32::MAX as usize {
+            return None;
+        }
+        Some(Self {
+            ops_limit,
+            mem_limit_kib: mem_limit_kib as u32,
+        })
+    }
+    /// Parameters for interactive logins: 2 passes over 64 MiB
+    pub fn interactive() -> Self {
+        Self {
+            ops_limit: 2,
+            mem_limit_kib: 64 * 1024,
+        }
+    }
+    /// Parameters for moderately sensitive data: 3 passes over 256 MiB
+    pub fn moderate() -> Self {
+        Self {
+            ops_limit: 3,
+            mem_limit_kib: 256 * 1024,
+        }
+    }
+    /// Parameters for highly sensitive data: 4 passes over 1 GiB
+    pub fn sensitive() -> Self {
+        Self {
+            ops_limit: 4,
+            mem_limit_kib: 1024 * 1024,
+        }
+    }
+    /// Returns the number of passes over the memory
+    pub fn ops_limit(&self) -> u32 {
+        self.ops_limit
+    }
+    /// Returns the amount of memory used in bytes
+    pub fn mem_limit(&self) -> usize {
+        self.mem_limit_kib as usize * 1024
+    }
+}
+
+/// Variable-length hash function H' from the Argon2 specification
+fn hash_long(out: &mut [u8], inputs: &[&[u8]]) {
+    let len = (out.len() as u32).to_le_bytes();
+    if out.len() <= 64 {
+        let mut state = Blake2b::new(out.len());
+        state.update(&len);
+        for input in inputs {
+            state.update(input);
+        }
+        out.copy_from_slice(&state.finalize());
+        return;
+    }
+    let mut state = Blake2b::new(64);
+    state.update(&len);
+    for input in inputs {
+        state.update(input);
+    }
+    let mut v = state.finalize();
+    let mut offset = 0;
+    while out.len() - offset > 64 {
+        out[offset..offset + 32].copy_from_slice(&v[..32]);
+        offset += 32;
+        let mut state = Blake2b::new(std::cmp::min(64, out.len() - offset));
+        state.update(&v);
+        v = state.finalize();
+    }
+    out[offset..].copy_from_slice(&v);
+}
+
+#[inline(always)]
+fn bla_mka(x: u64, y: u64) -> u64 {
+    let m = u64::from(x as u32) * u64::from(y as u32);
+    x.wrapping_add(y).wrapping_add(m.wrapping_mul(2))
+}
+
+#[inline(always)]
+fn gb(v: &mut Block, a: usize, b: usize, c: usize, d: usize) {
+    v[a] = bla_mka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = bla_mka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = bla_mka(v[a], v[b]);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = bla_mka(v[c], v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+/// Applies the BLAKE2b round function to 16 words, given by their indices
+#[inline(always)]
+fn permute(v: &mut Block, i: [usize; 16]) {
+    gb(v, i[0], i[4], i[8], i[12]);
+    gb(v, i[1], i[5], i[9], i[13]);
+    gb(v, i[2], i[6], i[10], i[14]);
+    gb(v, i[3], i[7], i[11], i[15]);
+    gb(v, i[0], i[5], i[10], i[15]);
+    gb(v, i[1], i[6], i[11], i[12]);
+    gb(v, i[2], i[7], i[8], i[13]);
+    gb(v, i[3], i[4], i[9], i[14]);
+}
+
+/// Compression function G. If `xor` is set, the result is xored into `next` instead of replacing
+/// it, as required for passes after the first one.
+fn fill_block(prev: &Block, reference: &Block, next: &mut Block, xor: bool) {
+    let mut r = [0u64; BLOCK_WORDS];
+    for i in 0..BLOCK_WORDS {
+        r[i] = prev[i] ^ reference[i];
+    }
+    let mut z = r;
+    for i in 0..8 {
+        let mut idx = [0usize; 16];
+        for (j, x) in idx.iter_mut().enumerate() {
+            *x = 16 * i + j;
+        }
+        permute(&mut z, idx);
+    }
+    for i in 0..8 {
+        let mut idx = [0usize; 16];
+        for (j, x) in idx.iter_mut().enumerate() {
+            *x = 2 * i + (j / 2) * 16 + (j % 2);
+        }
+        permute(&mut z, idx);
+    }
+    for i in 0..BLOCK_WORDS {
+        if xor {
+            next[i] ^= z[i] ^ r[i];
+        } else {
+            next[i] = z[i] ^ r[i];
+        }
+    }
+}
+
+struct Instance {
+    memory: Vec<Block>,
+    lanes: usize,
+    lane_length: usize,
+    segment_length: usize,
+    passes: u32,
+}
+
+impl Instance {
+    fn reference_index(
+        &self,
+        pass: u32,
+        slice: usize,
+        index: usize,
+        same_lane: bool,
+        pseudo_rand: u32,
+    ) -> usize {
+        let area = if pass == 0 {
+            if slice == 0 {
+                index - 1
+            } else if same_lane {
+                slice * self.segment_length + index - 1
+            } else if index == 0 {
+                slice * self.segment_length - 1
+            } else {
+                slice * self.segment_length
+            }
+        } else if same_lane {
+            self.lane_length - self.segment_length + index - 1
+        } else if index == 0 {
+            self.lane_length - self.segment_length - 1
+        } else {
+            self.lane_length - self.segment_length
+        };
+        let x = (u64::from(pseudo_rand) * u64::from(pseudo_rand)) >> 32;
+        let relative = area - 1 - ((area as u64 * x) >> 32) as usize;
+        let start = if pass == 0 || slice == SYNC_POINTS - 1 {
+            0
+        } else {
+            (slice + 1) * self.segment_length
+        };
+        (start + relative) % self.lane_length
+    }
+
+    fn fill_segment(&mut self, pass: u32, lane: usize, slice: usize) {
+        let zero = [0u64; BLOCK_WORDS];
+        let data_independent = pass == 0 && slice < SYNC_POINTS / 2;
+        let mut input = [0u64; BLOCK_WORDS];
+        let mut addresses = [0u64; BLOCK_WORDS];
+        input[0] = u64::from(pass);
+        input[1] = lane as u64;
+        input[2] = slice as u64;
+        input[3] = self.memory.len() as u64;
+        input[4] = u64::from(self.passes);
+        input[5] = u64::from(ARGON2ID);
+        let next_addresses = |input: &mut Block, addresses: &mut Block| {
+            input[6] += 1;
+            let mut tmp = [0u64; BLOCK_WORDS];
+            fill_block(&zero, input, &mut tmp, false);
+            fill_block(&zero, &tmp, addresses, false);
+        };
+        let start = if pass == 0 && slice == 0 {
+            // The first two blocks of each lane have already been generated
+            if data_independent {
+                next_addresses(&mut input, &mut addresses);
+            }
+            2
+        } else {
+            0
+        };
+        for i in start..self.segment_length {
+            let offset = lane * self.lane_length + slice * self.segment_length + i;
+            let prev = if slice == 0 && i == 0 {
+                offset + self.lane_length - 1
+            } else {
+                offset - 1
+            };
+            let pseudo_rand = if data_independent {
+                if i % BLOCK_WORDS == 0 {
+                    next_addresses(&mut input, &mut addresses);
+                }
+                addresses[i % BLOCK_WORDS]
+            } else {
+                self.memory[prev][0]
+            };
+            let ref_lane = if pass == 0 && slice == 0 {
+                lane
+            } else {
+                ((pseudo_rand >> 32) as usize) % self.lanes
+            };
+            let ref_index =
+                self.reference_index(pass, slice, i, ref_lane == lane, pseudo_rand as u32);
+            let reference = self.memory[ref_lane * self.lane_length + ref_index];
+            let prev = self.memory[prev];
+            fill_block(&prev, &reference, &mut self.memory[offset], pass != 0);
+        }
+    }
+}
+
+/// Computes the Argon2id hash with all of its parameters.
+fn argon2id_full(
+    out: &mut [u8],
+    password: &[u8],
+    salt: &[u8],
+    secret: &[u8],
+    ad: &[u8],
+    params: &Params,
+    lanes: u32,
+) {
+    let passes = params.ops_limit;
+    let mem_kib = params.mem_limit_kib;
+    let mut h0 = Blake2b::new(64);
+    for v in [lanes, out.len() as u32, mem_kib, passes, VERSION, ARGON2ID].iter() {
+        h0.update(&v.to_le_bytes());
+    }
+    for input in [password, salt, secret, ad].iter() {
+        h0.update(&(input.len() as u32).to_le_bytes());
+        h0.update(input);
+    }
+    let h0 = h0.finalize();
+
+    let lanes = lanes as usize;
+    let segment_length =
+        std::cmp::max(mem_kib as usize, 2 * SYNC_POINTS * lanes) / (SYNC_POINTS * lanes);
+    let lane_length = segment_length * SYNC_POINTS;
+    let mut instance = Instance {
+        memory: vec![[0u64; BLOCK_WORDS]; lane_length * lanes],
+        lanes,
+        lane_length,
+        segment_length,
+        passes,
+    };
+    let mut bytes = [0u8; 1024];
+    for lane in 0..lanes {
+        for i in 0..2u32 {
+            hash_long(
+                &mut bytes,
+                &[&h0, &i.to_le_bytes(), &(lane as u32).to_le_bytes()],
+            );
+            let block = &mut instance.memory[lane * lane_length + i as usize];
+            for (w, c) in block.iter_mut().zip(bytes.chunks(8)) {
+                let mut b = [0u8; 8];
+                b.copy_from_slice(c);
+                *w = u64::from_le_bytes(b);
+            }
+        }
+    }
+    for pass in 0..passes {
+        for slice in 0..SYNC_POINTS {
+            for lane in 0..lanes {
+                instance.fill_segment(pass, lane, slice);
+            }
+        }
+    }
+    let mut last = [0u64; BLOCK_WORDS];
+    for lane in 0..lanes {
+        for (a, b) in last
+            .iter_mut()
+            .zip(instance.memory[lane * lane_length + lane_length - 1].iter())
+        {
+            *a ^= b;
+        }
+    }
+    for (c, w) in bytes.chunks_mut(8).zip(last.iter()) {
+        c.copy_from_slice(&w.to_le_bytes());
+    }
+    hash_long(out, &[&bytes]);
+}
+
+/// Derives `out.len()` bytes from a password and a salt using Argon2id (`crypto_pwhash`).
+///
+/// Panics if the output is shorter than 16 bytes.
+pub fn argon2id(password: &[u8], salt: &[u8; SALT_BYTES], params: &Params, out: &mut [u8]) {
+    assert!(out.len() >= 16);
+    argon2id_full(out, password, salt, &[], &[], params, 1);
+}
+
+/// Derives a key suitable for `SecretBox::new` from a password and a salt
+pub fn derive_key(password: &[u8], salt: &[u8; SALT_BYTES], params: &Params) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    argon2id(password, salt, params, &mut key);
+    key
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() / 3 * 4 + 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..=chunk.len() {
+            out.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+        }
+    }
+    out
+}
+
+fn base64_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let v = BASE64.iter().position(|&x| x == c)? as u32;
+        acc = (acc << 6) | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // Reject non-canonical encodings
+    if bits >= 6 || acc & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// Hashes a password for storage (`crypto_pwhash_str`)
+///
+/// A random salt is generated. The returned string contains the algorithm, the parameters, the
+/// salt and the hash, and can be checked using `verify_str`.
+pub fn hash_str(password: &[u8], params: &Params) -> String {
+    use rand::RngCore;
+    let mut salt = [0u8; SALT_BYTES];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+    hash_str_with_salt(password, &salt, params)
+}
+
+fn hash_str_with_salt(password: &[u8], salt: &[u8; SALT_BYTES], params: &Params) -> String {
+    let mut hash = [0u8; 32];
+    argon2id(password, salt, params, &mut hash);
+    format!(
+        "$argon2id$v={}$m={},t={},p=1${}${}",
+        VERSION,
+        params.mem_limit_kib,
+        params.ops_limit,
+        base64_encode(salt),
+        base64_encode(&hash)
+    )
+}
+
+/// Parameters and salt read from a `$argon2id$` string
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Encoded {
+    /// Cost parameters
+    pub params: Params,
+    /// Number of lanes
+    pub lanes: u32,
+    /// Salt used for hashing
+    pub salt: Vec<u8>,
+    /// Hash of the password
+    pub hash: Vec<u8>,
+}
+
+impl Encoded {
+    /// Parses a `$argon2id$v=19$m=<kib>,t=<passes>,p=<lanes>$<salt>$<hash>` string
+    ///
+    /// Returns None if the string is malformed or uses a different algorithm or version
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split('$');
+        if !parts.next()?.is_empty() || parts.next()? != "argon2id" {
+            return None;
+        }
+        if parts.next()? != format!("v={}", VERSION) {
+            return None;
+        }
+        let mut params = parts.next()?.split(',');
+        let mut param = |name: &str| -> Option<u32> {
+            let p = params.next()?;
+            if !p.starts_with(name) || p[name.len()..].starts_with('+') {
+                return None;
+            }
+            p[name.len()..].parse().ok()
+        };
+        let mem_limit_kib = param("m=")?;
+        let ops_limit = param("t=")?;
+        let lanes = param("p=")?;
+        if params.next().is_some() || lanes == 0 || lanes > MAX_LANES || ops_limit == 0 {
+            return None;
+        }
+        if mem_limit_kib < 8 * lanes {
+            return None;
+        }
+        let salt = base64_decode(parts.next()?)?;
+        let hash = base64_decode(parts.next()?)?;
+        if parts.next().is_some() || salt.len() < 8 || hash.len() < 16 {
+            return None;
+        }
+        Some(Self {
+            params: Params {
+                ops_limit,
+                mem_limit_kib,
+            },
+            lanes,
+            salt,
+            hash,
+        })
+    }
+}
+
+/// Checks a password against a string created by `hash_str` (`crypto_pwhash_str_verify`)
+pub fn verify_str(encoded: &str, password: &[u8]) -> bool {
+    let encoded = match Encoded::parse(encoded) {
+        Some(e) => e,
+        None => return false,
+    };
+    let mut hash = vec![0u8; encoded.hash.len()];
+    argon2id_full(
+        &mut hash,
+        password,
+        &encoded.salt,
+        &[],
+        &[],
+        &encoded.params,
+        encoded.lanes,
+    );
+    crate::constant_time_eq(&hash, &encoded.hash)
+}
+
+/// Returns true if a string created by `hash_str` uses different parameters than `params`, and
+/// the password should be rehashed (`crypto_pwhash_str_needs_rehash`)
+pub fn needs_rehash(encoded: &str, params: &Params) -> bool {
+    match Encoded::parse(encoded) {
+        Some(e) => {
+            e.params != *params || e.lanes != 1 || e.salt.len() != SALT_BYTES || e.hash.len() != 32
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn rfc9106_vector() {
+        let mut out = [0u8; 32];
+        argon2id_full(
+            &mut out,
+            &[0x01; 32],
+            &[0x02; 16],
+            &[0x03; 8],
+            &[0x04; 12],
+            &Params {
+                ops_limit: 3,
+                mem_limit_kib: 32,
+            },
+            4,
+        );
+        assert_eq!(
+            out,
+            [
+                0x0d, 0x64, 0x0d, 0xf5, 0x8d, 0x78, 0x76, 0x6c, 0x08, 0xc0, 0x37, 0xa3, 0x4a, 0x8b,
+                0x53, 0xc9, 0xd0, 0x1e, 0xf0, 0x45, 0x2d, 0x75, 0xb6, 0x5e, 0xb5, 0x25, 0x20, 0xe9,
+                0x6b, 0x01, 0xe6, 0x59
+            ]
+        );
+    }
+    #[test]
+    fn encoded_string() {
+        let params = Params::new(2, 64 * 1024).unwrap();
+        let salt = *b"somesaltsomesalt";
+        let s = hash_str_with_salt(b"password", &salt, &params);
+        assert!(s.starts_with("$argon2id$v=19$m=64,t=2,p=1$c29tZXNhbHRzb21lc2FsdA$"));
+        let encoded = Encoded::parse(&s).unwrap();
+        assert_eq!(encoded.params, params);
+        assert_eq!(&encoded.salt[..], &salt[..]);
+        assert_eq!(
+            encoded.hash,
+            derive_key(b"password", &salt, &params).to_vec()
+        );
+        assert!(verify_str(&s, b"password"));
+        assert!(!verify_str(&s, b"passwore"));
+        assert!(!needs_rehash(&s, &params));
+        assert!(needs_rehash(&s, &Params::interactive()));
+        assert!(!verify_str(
+            "$argon2i$v=19$m=64,t=2,p=1$c29tZXNhbHQ$aGFzaA",
+            b"password"
+        ));
+        assert!(!verify_str(&s[..s.len() - 1], b"password"));
+        assert_eq!(
+            Encoded::parse(
+                "$argon2id$v=19$m=4294967295,t=2,p=536870912$c29tZXNhbHQ$aGFzaGhhc2hoYXNoaGFzaA"
+            ),
+            None
+        );
+        assert_eq!(
+            Encoded::parse(
+                "$argon2id$v=19$m=4294967295,t=2,p=16777216$c29tZXNhbHQ$aGFzaGhhc2hoYXNoaGFzaA"
+            ),
+            None
+        );
+    }
+    #[test]
+    fn presets() {
+        assert_eq!(Params::interactive(), Params::new(2, 67_108_864).unwrap());
+        assert_eq!(Params::moderate(), Params::new(3, 268_435_456).unwrap());
+        assert_eq!(Params::sensitive(), Params::new(4, 1_073_741_824).unwrap());
+        assert_eq!(Params::new(0, 67_108_864), None);
+        assert_eq!(Params::new(1, 8191), None);
+    }
+}
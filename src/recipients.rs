@@ -0,0 +1,305 @@
+//! Boxes that can be opened by several recipients
+//!
+//! The message is sealed once under a random data key. The data key is then wrapped separately for
+//! every recipient: with `SecretBox::seal_committing` and a random nonce for recipients that share
+//! a symmetric key with the sender, and with `crypto_box::seal_anonymous` for recipients
+//! identified by a public key (with the `curve25519` feature). A recipient only needs its own key
+//! to open the box.
+//!
+//! The payload is sealed with `SecretBox::seal_committing` as well, so it can only be opened with
+//! the data key it was sealed with. Otherwise a malicious sender could wrap different data keys
+//! for different recipients that open the same payload to different messages.
+//!
+//! Every wrapped key is preceded by a 4-byte hint. For a symmetric key, the hint is taken from a
+//! subkey derived with the context `SBoxHint`, so it reveals nothing about the key itself; for a
+//! public key, it is a BLAKE2b hash of the public key. When opening, only the wrapped keys with a
+//! matching hint are tried, so opening does not have to attempt every wrapped key. The hints make
+//! it possible to tell whether two boxes were sent to the same recipient.
+//!
+//! Anybody who can open the box knows the data key, and can therefore create a box that the other
+//! recipients will accept. The box does not authenticate the sender among the recipients.
+//!
+//! The format of a box is
+//! `algorithm ID (1 byte) || number of recipients (2 bytes, big endian) || wrapped keys ||
+//! commitment || tag || ciphertext`, where every wrapped key is `kind (1 byte) || hint (4 bytes)
+//! || sealed data key`. A sealed data key for a symmetric key is `nonce || commitment || tag ||
+//! encrypted key`. The payload is sealed with a zero nonce, as every data key is only used once.
+#[cfg(feature = "curve25519")]
+use crate::blake2b::Blake2b;
+use crate::commit::COMMITMENT_BYTES;
+#[cfg(feature = "curve25519")]
+use crate::crypto_box::{self, PublicKey, SecretKey};
+use crate::{kdf, CipherType, SecretBox};
+use rand::RngCore;
+
+/// Size of a recipient hint
+pub const HINT_BYTES: usize = 4;
+
+/// Personalization of the hint hash of public keys
+#[cfg(feature = "curve25519")]
+const PERSONAL: [u8; 16] = *b"SBoxRecipient\0\0\0";
+
+/// Context used to derive the hint of symmetric keys
+const HINT_CONTEXT: [u8; 8] = *b"SBoxHint";
+
+/// Kind of a wrapped key for a symmetric key
+const KIND_KEY: u8 = 0;
+/// Kind of a wrapped key for a public key
+const KIND_PUBLIC_KEY: u8 = 1;
+
+/// Recipient of a box
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Recipient<'a> {
+    /// Recipient that shares a symmetric key with the sender
+    Key(&'a SecretBox),
+    /// Recipient identified by an X25519 public key
+    #[cfg(feature = "curve25519")]
+    PublicKey(&'a PublicKey),
+}
+
+impl<'a> Recipient<'a> {
+    fn kind(self) -> u8 {
+        match self {
+            Recipient::Key(_) => KIND_KEY,
+            #[cfg(feature = "curve25519")]
+            Recipient::PublicKey(_) => KIND_PUBLIC_KEY,
+        }
+    }
+    /// Returns the hint that identifies this recipient in a box
+    pub fn hint(self) -> [u8; HINT_BYTES] {
+        let mut hint = [0u8; HINT_BYTES];
+        match self {
+            Recipient::Key(key) => {
+                hint.copy_from_slice(&kdf::derive_from_key(&key.key, 0, HINT_CONTEXT)[..HINT_BYTES])
+            }
+            #[cfg(feature = "curve25519")]
+            Recipient::PublicKey(pk) => {
+                let mut hash = Blake2b::with_params(HINT_BYTES, &[], [0u8; 16], PERSONAL);
+                hash.update(pk.as_bytes());
+                hint.copy_from_slice(&hash.finalize());
+            }
+        }
+        hint
+    }
+    /// Wraps a data key for this recipient
+    ///
+    /// Returns None if the recipient's public key is a low-order point
+    fn wrap(self, data_key: &[u8; 32]) -> Option<Vec<u8>> {
+        match self {
+            Recipient::Key(key) => {
+                let mut nonce = [0u8; 24];
+                rand::rngs::OsRng.fill_bytes(&mut nonce);
+                let mut out = nonce.to_vec();
+                out.extend_from_slice(&key.seal_committing(data_key, nonce));
+                Some(out)
+            }
+            #[cfg(feature = "curve25519")]
+            Recipient::PublicKey(pk) => crypto_box::seal_anonymous(pk, data_key),
+        }
+    }
+}
+
+/// Returns the size of a wrapped key of the given kind, without kind and hint
+fn wrapped_len(kind: u8) -> Option<usize> {
+    match kind {
+        KIND_KEY => Some(24 + COMMITMENT_BYTES + 16 + 32),
+        KIND_PUBLIC_KEY => Some(32 + 16 + 32),
+        _ => None,
+    }
+}
+
+/// Wrapped data key read from a box
+struct Wrapped<'a> {
+    kind: u8,
+    hint: &'a [u8],
+    sealed: &'a [u8],
+}
+
+/// Splits a box into the payload cipher, the wrapped keys and the sealed payload
+fn parse(data: &[u8]) -> Option<(CipherType, Vec<Wrapped<'_>>, &[u8])> {
+    if data.len() < 3 {
+        return None;
+    }
+    let cipher = CipherType::from_id(data[0])?;
+    let count = u16::from_be_bytes([data[1], data[2]]) as usize;
+    let mut rest = &data[3..];
+    let mut wrapped = Vec::with_capacity(count);
+    for _ in 0..count {
+        if rest.is_empty() {
+            return None;
+        }
+        let len = 1 + HINT_BYTES + wrapped_len(rest[0])?;
+        if rest.len() < len {
+            return None;
+        }
+        wrapped.push(Wrapped {
+            kind: rest[0],
+            hint: &rest[1..1 + HINT_BYTES],
+            sealed: &rest[1 + HINT_BYTES..len],
+        });
+        rest = &rest[len..];
+    }
+    Some((cipher, wrapped, rest))
+}
+
+/// Opens the payload with the first wrapped key that matches the recipient
+fn open_with<F>(data: &[u8], recipient: Recipient, unwrap: F) -> Option<Vec<u8>>
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>>,
+{
+    let (cipher, wrapped, payload) = parse(data)?;
+    let hint = recipient.hint();
+    let data_key = wrapped
+        .iter()
+        .filter(|w| w.kind == recipient.kind() && w.hint == hint)
+        .filter_map(|w| unwrap(w.sealed))
+        .next()?;
+    SecretBox::new(&data_key, cipher)?.open_committing(payload, [0u8; 24])
+}
+
+/// Seals a message for several recipients. The payload is encrypted with the given cipher.
+///
+/// Returns None if there are no recipients or more than 65535, or if the public key of a
+/// recipient is a low-order point
+pub fn seal(recipients: &[Recipient], message: &[u8], cipher: CipherType) -> Option<Vec<u8>> {
+    if recipients.is_empty() || recipients.len() > u16::MAX as usize {
+        return None;
+    }
+    let mut data_key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut data_key);
+    let mut out = vec![cipher.id()];
+    out.extend_from_slice(&(recipients.len() as u16).to_be_bytes());
+    for recipient in recipients {
+        out.push(recipient.kind());
+        out.extend_from_slice(&recipient.hint());
+        out.extend_from_slice(&recipient.wrap(&data_key)?);
+    }
+    let payload = SecretBox {
+        key: data_key,
+        cipher,
+    };
+    out.extend_from_slice(&payload.seal_committing(message, [0u8; 24]));
+    Some(out)
+}
+
+/// Opens a box with a symmetric recipient key
+///
+/// Returns None if the box was not sealed for this key or is not authentic
+pub fn open(key: &SecretBox, data: &[u8]) -> Option<Vec<u8>> {
+    open_with(data, Recipient::Key(key), |sealed| {
+        let mut nonce = [0u8; 24];
+        nonce.copy_from_slice(&sealed[..24]);
+        key.open_committing(&sealed[24..], nonce)
+    })
+}
+
+/// Opens a box with the key pair of a recipient
+///
+/// Returns None if the box was not sealed for this public key or is not authentic
+#[cfg(feature = "curve25519")]
+pub fn open_with_keypair(keypair: &(PublicKey, SecretKey), data: &[u8]) -> Option<Vec<u8>> {
+    open_with(data, Recipient::PublicKey(&keypair.0), |sealed| {
+        crypto_box::open_anonymous(keypair, sealed)
+    })
+}
+
+/// Returns the hints of all recipients of a box, in order
+pub fn hints(data: &[u8]) -> Option<Vec<[u8; HINT_BYTES]>> {
+    let (_, wrapped, _) = parse(data)?;
+    Some(
+        wrapped
+            .iter()
+            .map(|w| {
+                let mut hint = [0u8; HINT_BYTES];
+                hint.copy_from_slice(w.hint);
+                hint
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symmetric() {
+        let red = SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap();
+        let blue = SecretBox::new([2u8; 32], CipherType::Chacha20).unwrap();
+        let green = SecretBox::new([3u8; 32], CipherType::Salsa20).unwrap();
+        let recipients = [Recipient::Key(&red), Recipient::Key(&blue)];
+        let sealed = seal(&recipients, b"quarterly report", CipherType::Chacha20).unwrap();
+        assert_eq!(sealed.len(), 3 + 2 * (1 + 4 + 104) + 32 + 16 + 16);
+        assert_eq!(
+            hints(&sealed).unwrap(),
+            vec![recipients[0].hint(), recipients[1].hint()]
+        );
+        assert_eq!(open(&red, &sealed).unwrap(), b"quarterly report".to_vec());
+        assert_eq!(open(&blue, &sealed).unwrap(), b"quarterly report".to_vec());
+        assert_eq!(open(&green, &sealed), None);
+        assert_eq!(seal(&[], b"nobody", CipherType::Salsa20), None);
+    }
+
+    #[test]
+    fn malformed() {
+        let red = SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap();
+        let sealed = seal(&[Recipient::Key(&red)], b"data", CipherType::Salsa20).unwrap();
+        for len in 0..sealed.len() {
+            assert_eq!(open(&red, &sealed[..len]), None);
+        }
+        // Wrong hint, unknown kind, tampered wrapped key and tampered payload
+        for &i in [0, 3, 4, 10, sealed.len() - 1].iter() {
+            let mut forged = sealed.clone();
+            forged[i] ^= 1;
+            assert_eq!(open(&red, &forged), None);
+        }
+    }
+
+    #[test]
+    fn key_commitment() {
+        let red = SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap();
+        let blue = SecretBox::new([2u8; 32], CipherType::Salsa20).unwrap();
+        let recipients = [Recipient::Key(&red), Recipient::Key(&blue)];
+        let sealed = seal(&recipients, b"pay Alice", CipherType::Salsa20).unwrap();
+        // A malicious sender wraps a second data key for blue, next to the same payload. The
+        // payload is committed to the first data key, so blue rejects the box.
+        let wrapped = 1 + HINT_BYTES + 104;
+        let start = 3 + wrapped + 1 + HINT_BYTES;
+        let mut crafted = sealed.clone();
+        crafted[start..start + 104].copy_from_slice(&recipients[1].wrap(&[9u8; 32]).unwrap());
+        assert_eq!(open(&red, &crafted).unwrap(), b"pay Alice".to_vec());
+        assert_eq!(open(&blue, &crafted), None);
+
+        // The hint is derived from a subkey, not from the key itself
+        let subkey = kdf::derive_from_key(&[1u8; 32], 0, *b"SBoxHint");
+        assert_eq!(&recipients[0].hint()[..], &subkey[..HINT_BYTES]);
+    }
+
+    #[cfg(feature = "curve25519")]
+    #[test]
+    fn public_keys() {
+        let alice = crypto_box::keypair(&mut rand::rngs::OsRng);
+        let bob = crypto_box::keypair(&mut rand::rngs::OsRng);
+        let red = SecretBox::new([1u8; 32], CipherType::Salsa20).unwrap();
+        let recipients = [
+            Recipient::PublicKey(&alice.0),
+            Recipient::Key(&red),
+            Recipient::PublicKey(&bob.0),
+        ];
+        let sealed = seal(&recipients, b"minutes", CipherType::Salsa20).unwrap();
+        assert_eq!(
+            open_with_keypair(&alice, &sealed).unwrap(),
+            b"minutes".to_vec()
+        );
+        assert_eq!(
+            open_with_keypair(&bob, &sealed).unwrap(),
+            b"minutes".to_vec()
+        );
+        assert_eq!(open(&red, &sealed).unwrap(), b"minutes".to_vec());
+        let eve = crypto_box::keypair(&mut rand::rngs::OsRng);
+        assert_eq!(open_with_keypair(&eve, &sealed), None);
+        let low_order = PublicKey::from([0u8; 32]);
+        let recipients = [Recipient::Key(&red), Recipient::PublicKey(&low_order)];
+        assert_eq!(seal(&recipients, b"minutes", CipherType::Salsa20), None);
+    }
+}
@@ -0,0 +1,168 @@
+//! Replay protection for boxes sealed with counter-based nonces
+//!
+//! `ReplayWindow` is a sliding bitmap as described in RFC 6479. It remembers which counter values
+//! have been seen among the most recent ones, and rejects anything older than the window.
+//! Every sender needs its own window, as the counters of different senders are unrelated.
+use crate::nonce::split_nonce;
+use crate::SecretBox;
+use std::fmt;
+
+/// Errors returned when a box is rejected
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReplayError {
+    /// The box could not be authenticated
+    Unauthentic,
+    /// The box has been received before
+    Replayed,
+    /// The box is older than the window and it cannot be known whether it was received before
+    TooOld,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Unauthentic => write!(f, "box could not be authenticated"),
+            ReplayError::Replayed => write!(f, "box has already been received"),
+            ReplayError::TooOld => write!(f, "box is outside of the replay window"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Sliding window of recently received counter values
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReplayWindow {
+    bitmap: Vec<u64>,
+    last: u64,
+}
+
+impl Default for ReplayWindow {
+    /// Creates a window that accepts counters up to 1984 values behind the newest one
+    fn default() -> Self {
+        Self::new(32)
+    }
+}
+
+impl ReplayWindow {
+    /// Creates a new window made of `words` 64-bit words. It accepts counter values that are up to
+    /// `(words - 1) * 64` behind the newest one.
+    ///
+    /// Panics if `words` is not a power of two or less than 2
+    pub fn new(words: usize) -> Self {
+        assert!(words >= 2 && words.is_power_of_two());
+        Self {
+            bitmap: vec![0; words],
+            last: 0,
+        }
+    }
+    /// Returns how far behind the newest counter value a counter value may be
+    pub fn size(&self) -> u64 {
+        (self.bitmap.len() as u64 - 1) * 64
+    }
+    fn mask(&self) -> u64 {
+        self.bitmap.len() as u64 - 1
+    }
+    /// Checks whether a counter value would be accepted, without recording it
+    pub fn check(&self, counter: u64) -> Result<(), ReplayError> {
+        if counter > self.last {
+            return Ok(());
+        }
+        if self.last - counter > self.size() {
+            return Err(ReplayError::TooOld);
+        }
+        let word = self.bitmap[((counter >> 6) & self.mask()) as usize];
+        if word & (1 << (counter & 63)) != 0 {
+            return Err(ReplayError::Replayed);
+        }
+        Ok(())
+    }
+    /// Checks a counter value and records it if it is accepted.
+    ///
+    /// Only call this for authenticated messages, or an attacker can advance the window.
+    pub fn update(&mut self, counter: u64) -> Result<(), ReplayError> {
+        self.check(counter)?;
+        let index = counter >> 6;
+        if counter > self.last {
+            let current = self.last >> 6;
+            let diff = std::cmp::min(index - current, self.bitmap.len() as u64);
+            for i in 1..=diff {
+                let i = ((current + i) & self.mask()) as usize;
+                self.bitmap[i] = 0;
+            }
+            self.last = counter;
+        }
+        let i = (index & self.mask()) as usize;
+        self.bitmap[i] |= 1 << (counter & 63);
+        Ok(())
+    }
+}
+
+impl SecretBox {
+    /// Opens a box created by `seal_next` (nonce followed by the box) at most once.
+    ///
+    /// The box is authenticated first. Only authentic boxes are checked against the window and
+    /// recorded in it, so forged packets cannot influence which packets are accepted.
+    pub fn open_once(
+        &self,
+        data: &[u8],
+        window: &mut ReplayWindow,
+    ) -> Result<Vec<u8>, ReplayError> {
+        let plain = self.easy_unseal(data).ok_or(ReplayError::Unauthentic)?;
+        let mut nonce = [0u8; 24];
+        nonce.copy_from_slice(&data[..24]);
+        window.update(split_nonce(nonce).1)?;
+        Ok(plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nonce::CounterNonce;
+    use crate::CipherType;
+
+    #[test]
+    fn window() {
+        let mut w = ReplayWindow::new(4);
+        assert_eq!(w.size(), 192);
+        w.update(0).unwrap();
+        assert_eq!(w.update(0), Err(ReplayError::Replayed));
+        w.update(5).unwrap();
+        w.update(3).unwrap();
+        assert_eq!(w.update(3), Err(ReplayError::Replayed));
+        w.update(1000).unwrap();
+        assert_eq!(w.check(5), Err(ReplayError::TooOld));
+        assert_eq!(w.check(807), Err(ReplayError::TooOld));
+        w.update(808).unwrap();
+        w.update(999).unwrap();
+        assert_eq!(w.update(808), Err(ReplayError::Replayed));
+        // Jumping far ahead clears the whole bitmap
+        w.update(100_000).unwrap();
+        w.update(99_999).unwrap();
+        assert_eq!(w.update(1000), Err(ReplayError::TooOld));
+    }
+
+    #[test]
+    fn open_once() {
+        let s = SecretBox::new([8u8; 32], CipherType::Salsa20).unwrap();
+        let mut nonces = CounterNonce::new(0);
+        let first = s.seal_next(&mut nonces, b"first").unwrap();
+        let second = s.seal_next(&mut nonces, b"second").unwrap();
+        let mut w = ReplayWindow::default();
+
+        assert_eq!(s.open_once(&second, &mut w).unwrap(), b"second".to_vec());
+        assert_eq!(s.open_once(&first, &mut w).unwrap(), b"first".to_vec());
+        assert_eq!(s.open_once(&first, &mut w), Err(ReplayError::Replayed));
+
+        // A forged packet with a far-ahead counter must not move the window
+        let mut forged = s
+            .seal_next(&mut CounterNonce::new(1 << 40), b"forged")
+            .unwrap();
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert_eq!(s.open_once(&forged, &mut w), Err(ReplayError::Unauthentic));
+        let third = s.seal_next(&mut nonces, b"third").unwrap();
+        assert_eq!(s.open_once(&third, &mut w).unwrap(), b"third".to_vec());
+    }
+}
@@ -0,0 +1,290 @@
+//! Encryption of RTP packets as done by Discord voice connections
+//!
+//! The RTP header (including the CSRC list) is sent in the clear, everything after it is sealed
+//! with XSalsa20-Poly1305. If the header has the extension bit set, the header extension is part
+//! of the sealed data. The modes differ in how the nonce is chosen:
+//!
+//! - `xsalsa20_poly1305`: the first 12 bytes of the RTP header, padded with zeroes
+//! - `xsalsa20_poly1305_suffix`: 24 random bytes, appended to the packet
+//! - `xsalsa20_poly1305_lite`: a 32-bit big endian counter, padded with zeroes. The 4 counter
+//!   bytes are appended to the packet.
+use crate::{CipherType, SecretBox};
+
+/// Size of the fixed part of an RTP header
+pub const HEADER_BYTES: usize = 12;
+
+/// Discord voice encryption mode
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Nonce derived from the RTP header
+    Normal,
+    /// Random 24-byte nonce appended to the packet
+    Suffix,
+    /// Incrementing 4-byte nonce appended to the packet
+    Lite,
+}
+
+impl Mode {
+    /// Returns the name of the mode as used in the voice gateway protocol
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Normal => "xsalsa20_poly1305",
+            Mode::Suffix => "xsalsa20_poly1305_suffix",
+            Mode::Lite => "xsalsa20_poly1305_lite",
+        }
+    }
+    /// Returns the mode with the given name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "xsalsa20_poly1305" => Some(Mode::Normal),
+            "xsalsa20_poly1305_suffix" => Some(Mode::Suffix),
+            "xsalsa20_poly1305_lite" => Some(Mode::Lite),
+            _ => None,
+        }
+    }
+    /// Returns the number of bytes appended to the packet for the nonce
+    pub fn nonce_suffix_len(self) -> usize {
+        match self {
+            Mode::Normal => 0,
+            Mode::Suffix => 24,
+            Mode::Lite => 4,
+        }
+    }
+}
+
+/// Parsed fixed RTP header
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RtpHeader {
+    /// Whether the packet is padded
+    pub padding: bool,
+    /// Whether a header extension follows the header
+    pub extension: bool,
+    /// Number of CSRC identifiers following the fixed header
+    pub csrc_count: u8,
+    /// Marker bit
+    pub marker: bool,
+    /// Payload type
+    pub payload_type: u8,
+    /// Sequence number
+    pub sequence: u16,
+    /// Timestamp
+    pub timestamp: u32,
+    /// Synchronization source identifier
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    /// Parses the header of an RTP packet
+    ///
+    /// Returns None if the packet is too short or is not an RTP version 2 packet
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < HEADER_BYTES || packet[0] >> 6 != 2 {
+            return None;
+        }
+        let header = Self {
+            padding: packet[0] & 0x20 != 0,
+            extension: packet[0] & 0x10 != 0,
+            csrc_count: packet[0] & 0x0f,
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            ssrc: u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]),
+        };
+        if packet.len() < header.header_len() {
+            return None;
+        }
+        Some(header)
+    }
+    /// Returns the length of the header in the clear, including the CSRC list
+    pub fn header_len(&self) -> usize {
+        HEADER_BYTES + 4 * self.csrc_count as usize
+    }
+    /// Returns the offset of the payload in a decrypted packet, skipping the header extension,
+    /// and the length of the payload without padding.
+    ///
+    /// Returns None if the extension or padding is malformed
+    pub fn payload_range(&self, packet: &[u8]) -> Option<(usize, usize)> {
+        let mut start = self.header_len();
+        if self.extension {
+            if packet.len() < start + 4 {
+                return None;
+            }
+            let words = u16::from_be_bytes([packet[start + 2], packet[start + 3]]) as usize;
+            start += 4 + 4 * words;
+        }
+        let mut end = packet.len();
+        if self.padding {
+            end = end.checked_sub(*packet.last()? as usize)?;
+        }
+        if start > end {
+            return None;
+        }
+        Some((start, end))
+    }
+}
+
+/// Seals and opens RTP packets for one voice connection
+#[derive(Clone, Debug)]
+pub struct RtpCrypto {
+    secret_box: SecretBox,
+    mode: Mode,
+    /// Next nonce of the `Lite` mode, or None once all values have been used
+    lite_nonce: Option<u32>,
+}
+
+impl RtpCrypto {
+    /// Creates a new instance with the secret key received from the voice gateway
+    pub fn new(key: [u8; 32], mode: Mode) -> Self {
+        Self {
+            secret_box: SecretBox {
+                key,
+                cipher: CipherType::Salsa20,
+            },
+            mode,
+            lite_nonce: Some(0),
+        }
+    }
+    /// Returns the encryption mode
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+    /// Encrypts an RTP packet. Everything after the header and CSRC list is sealed.
+    ///
+    /// Returns None if the packet does not have a valid RTP header, or if all 2^32 nonces of the
+    /// `Lite` mode have been used. A new key is needed in that case.
+    pub fn seal(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let header_len = RtpHeader::parse(packet)?.header_len();
+        let mut nonce = [0u8; 24];
+        match self.mode {
+            Mode::Normal => nonce[..HEADER_BYTES].copy_from_slice(&packet[..HEADER_BYTES]),
+            Mode::Suffix => {
+                use rand::RngCore;
+                rand::rngs::OsRng.fill_bytes(&mut nonce);
+            }
+            Mode::Lite => {
+                let counter = self.lite_nonce?;
+                nonce[..4].copy_from_slice(&counter.to_be_bytes());
+                self.lite_nonce = counter.checked_add(1);
+            }
+        }
+        let sealed = self.secret_box.seal(&packet[header_len..], nonce);
+        let mut out = Vec::with_capacity(header_len + sealed.len() + self.mode.nonce_suffix_len());
+        out.extend_from_slice(&packet[..header_len]);
+        out.extend_from_slice(&sealed);
+        out.extend_from_slice(&nonce[..self.mode.nonce_suffix_len()]);
+        Some(out)
+    }
+    /// Decrypts an RTP packet. The returned packet contains the header, the header extension (if
+    /// any) and the payload. Use `RtpHeader::payload_range` to find the payload.
+    ///
+    /// Returns None if the packet is malformed or not authentic
+    pub fn open(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let header_len = RtpHeader::parse(packet)?.header_len();
+        let suffix_len = self.mode.nonce_suffix_len();
+        if packet.len() < header_len + 16 + suffix_len {
+            return None;
+        }
+        let body_end = packet.len() - suffix_len;
+        let mut nonce = [0u8; 24];
+        match self.mode {
+            Mode::Normal => nonce[..HEADER_BYTES].copy_from_slice(&packet[..HEADER_BYTES]),
+            Mode::Suffix | Mode::Lite => nonce[..suffix_len].copy_from_slice(&packet[body_end..]),
+        }
+        let plain = self
+            .secret_box
+            .unseal(&packet[header_len..body_end], nonce)?;
+        let mut out = Vec::with_capacity(header_len + plain.len());
+        out.extend_from_slice(&packet[..header_len]);
+        out.extend_from_slice(&plain);
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(extension: bool) -> Vec<u8> {
+        let mut p = vec![
+            0x80 | if extension { 0x10 } else { 0 } | 1,
+            0x78,
+            0x12,
+            0x34,
+            0,
+            0,
+            0x03,
+            0xc0,
+            0xde,
+            0xad,
+            0xbe,
+            0xef,
+            // CSRC
+            1,
+            2,
+            3,
+            4,
+        ];
+        if extension {
+            p.extend_from_slice(&[0xbe, 0xde, 0, 1, 0x10, 0xff, 0, 0]);
+        }
+        p.extend_from_slice(b"opus frame");
+        p
+    }
+
+    #[test]
+    fn header() {
+        let p = packet(true);
+        let h = RtpHeader::parse(&p).unwrap();
+        assert_eq!(h.sequence, 0x1234);
+        assert_eq!(h.timestamp, 960);
+        assert_eq!(h.ssrc, 0xdead_beef);
+        assert_eq!(h.payload_type, 0x78);
+        assert_eq!(h.header_len(), 16);
+        let (start, end) = h.payload_range(&p).unwrap();
+        assert_eq!(&p[start..end], b"opus frame");
+        assert_eq!(RtpHeader::parse(&p[..15]), None);
+        assert_eq!(RtpHeader::parse(&[0u8; 12]), None);
+    }
+
+    #[test]
+    fn modes() {
+        for &mode in [Mode::Normal, Mode::Suffix, Mode::Lite].iter() {
+            assert_eq!(Mode::from_name(mode.name()), Some(mode));
+            let mut sender = RtpCrypto::new([6u8; 32], mode);
+            let receiver = RtpCrypto::new([6u8; 32], mode);
+            for &extension in [false, true].iter() {
+                let p = packet(extension);
+                let sealed = sender.seal(&p).unwrap();
+                assert_eq!(sealed.len(), p.len() + 16 + mode.nonce_suffix_len());
+                assert_eq!(&sealed[..16], &p[..16]);
+                assert_ne!(&sealed[16..p.len()], &p[16..]);
+                assert_eq!(receiver.open(&sealed).unwrap(), p);
+                let mut forged = sealed.clone();
+                forged[2] ^= 1;
+                if mode == Mode::Normal {
+                    // The header is part of the nonce
+                    assert_eq!(receiver.open(&forged), None);
+                }
+                assert_eq!(receiver.open(&sealed[..20]), None);
+            }
+        }
+    }
+
+    #[test]
+    fn lite_nonce() {
+        let mut sender = RtpCrypto::new([6u8; 32], Mode::Lite);
+        let p = packet(false);
+        let first = sender.seal(&p).unwrap();
+        let second = sender.seal(&p).unwrap();
+        assert_eq!(&first[first.len() - 4..], &[0, 0, 0, 0]);
+        assert_eq!(&second[second.len() - 4..], &[0, 0, 0, 1]);
+
+        // The counter is never reused
+        sender.lite_nonce = Some(u32::MAX);
+        let last = sender.seal(&p).unwrap();
+        assert_eq!(&last[last.len() - 4..], &[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(sender.seal(&p), None);
+        assert_eq!(sender.seal(&p), None);
+    }
+}
@@ -44,9 +44,9 @@ impl XSalsa20 {
             ],
         }
     }
-    /// Generates a single block of Salsa20 random data
-    pub fn generate_block(&mut self) -> Option<Vec<u8>> {
-        let input = [
+    /// Returns the input of the block function for the current counter value
+    pub(crate) fn state(&self) -> [u32; 16] {
+        [
             SIGMA[0],
             self.key[0],
             self.key[1],
@@ -63,7 +63,11 @@ impl XSalsa20 {
             self.key[6],
             self.key[7],
             SIGMA[3],
-        ];
+        ]
+    }
+    /// Generates a single block of Salsa20 random data
+    pub fn generate_block(&mut self) -> Option<Vec<u8>> {
+        let input = self.state();
         let output = salsa20(input);
         self.ctr = self.ctr.checked_add(1)?;
         let mut out_bytes = Vec::new();
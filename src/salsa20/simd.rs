@@ -89,6 +89,18 @@ pub fn salsa20(input: [u32; 16]) -> [u32; 16] {
     buf
 }
 
+/// Salsa20/8, the reduced-round variant of the salsa20 hash function used by scrypt
+pub fn salsa20_8(input: [u32; 16]) -> [u32; 16] {
+    // Initialize the 4 SIMD regs
+    let (i0, i1, i2, i3) = prepare(input);
+    // Calculate salsa20/8
+    let (o0, o1, o2, o3) = (0..4).fold((i0, i1, i2, i3), |(i0, i1, i2, i3), _| {
+        double_round(i0, i1, i2, i3)
+    });
+    // Convert it back into a usable format
+    finalize(i0 + o0, i1 + o1, i2 + o2, i3 + o3)
+}
+
 pub(crate) fn salsa20_rounds(input: [u32; 16]) -> [u32; 16] {
     // Initialize the 4 SIMD regs
     let (i0, i1, i2, i3) = prepare(input);
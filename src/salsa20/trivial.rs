@@ -45,6 +45,16 @@ pub fn salsa20(mut input: [u32; 16]) -> [u32; 16] {
     buf
 }
 
+/// Salsa20/8, the reduced-round variant of the salsa20 hash function used by scrypt
+pub fn salsa20_8(mut input: [u32; 16]) -> [u32; 16] {
+    let mut buf = (0..4).fold(input, |out, _| double_round(out));
+    for i in 0..16 {
+        buf[i] = buf[i].wrapping_add(input[i]);
+        input[i] = 0; // Clear cleartext data from stack
+    }
+    buf
+}
+
 #[inline(always)]
 pub(crate) fn salsa20_rounds(input: [u32; 16]) -> [u32; 16] {
     (0..10).fold(input, |out, _| double_round(out))
@@ -0,0 +1,163 @@
+//! The scrypt password-based key derivation function (RFC 7914)
+//!
+//! scrypt makes brute-forcing passwords expensive by requiring a large amount of memory. It mixes
+//! the output of PBKDF2-HMAC-SHA256 using the Salsa20/8 core.
+use crate::salsa20::implementation::salsa20_8;
+use crate::sha256::pbkdf2_hmac_sha256;
+
+/// scrypt cost parameters
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Params {
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+impl Params {
+    /// Creates a new set of parameters. The CPU/memory cost is `N = 2^log_n`, `r` is the block
+    /// size and `p` the parallelization parameter. Memory usage is about `128 * r * N` bytes.
+    ///
+    /// Returns None if the parameters are out of range
+    pub fn new(log_n: u8, r: u32, p: u32) -> Option<Self> {
+        if log_n == 0 || r == 0 || p == 0 {
+            return None;
+        }
+        // RFC 7914 requires N < 2^(128 * r / 8) and p <= (2^32 - 1) * 32 / (128 * r)
+        if u64::from(log_n) >= 16 * u64::from(r) || u32::from(log_n) >= usize::BITS {
+            return None;
+        }
+        if u64::from(r) * u64::from(p) >= 1 << 30 {
+            return None;
+        }
+        // `ro_mix` allocates `128 * r * N` bytes and the PBKDF2 output takes `128 * r * p` bytes
+        let block_size = 128usize.checked_mul(r as usize)?;
+        for &count in [1usize << log_n, p as usize].iter() {
+            if block_size.checked_mul(count)? > isize::MAX as usize {
+                return None;
+            }
+        }
+        Some(Self { log_n, r, p })
+    }
+    /// Returns the base-2 logarithm of the CPU/memory cost
+    pub fn log_n(&self) -> u8 {
+        self.log_n
+    }
+    /// Returns the block size
+    pub fn r(&self) -> u32 {
+        self.r
+    }
+    /// Returns the parallelization parameter
+    pub fn p(&self) -> u32 {
+        self.p
+    }
+}
+
+impl Default for Params {
+    /// Parameters recommended for interactive logins (`N = 2^15`, `r = 8`, `p = 1`)
+    fn default() -> Self {
+        Self {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+fn block_mix(input: &[u32], output: &mut [u32], r: usize) {
+    let mut x = [0u32; 16];
+    x.copy_from_slice(&input[(2 * r - 1) * 16..]);
+    for i in 0..2 * r {
+        for (a, b) in x.iter_mut().zip(input[i * 16..(i + 1) * 16].iter()) {
+            *a ^= b;
+        }
+        x = salsa20_8(x);
+        // Even blocks go into the first half of the output, odd blocks into the second half
+        let j = (i / 2) + (i % 2) * r;
+        output[j * 16..(j + 1) * 16].copy_from_slice(&x);
+    }
+}
+
+fn ro_mix(block: &mut [u8], r: usize, n: usize) {
+    let words = 32 * r;
+    let mut x: Vec<u32> = block
+        .chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    let mut y = vec![0u32; words];
+    let mut v = vec![0u32; words * n];
+    for i in 0..n {
+        v[i * words..(i + 1) * words].copy_from_slice(&x);
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+    for _ in 0..n {
+        let j = (u64::from(x[words - 16]) | (u64::from(x[words - 15]) << 32)) as usize & (n - 1);
+        for (a, b) in x.iter_mut().zip(v[j * words..(j + 1) * words].iter()) {
+            *a ^= b;
+        }
+        block_mix(&x, &mut y, r);
+        std::mem::swap(&mut x, &mut y);
+    }
+    for (c, w) in block.chunks_mut(4).zip(x.iter()) {
+        c.copy_from_slice(&w.to_le_bytes());
+    }
+}
+
+/// Derives `out.len()` bytes from a password and a salt
+pub fn scrypt(password: &[u8], salt: &[u8], params: &Params, out: &mut [u8]) {
+    let r = params.r as usize;
+    let block_size = 128 * r;
+    let mut b = vec![0u8; block_size * params.p as usize];
+    pbkdf2_hmac_sha256(password, salt, 1, &mut b);
+    for block in b.chunks_mut(block_size) {
+        ro_mix(block, r, 1 << params.log_n);
+    }
+    pbkdf2_hmac_sha256(password, &b, 1, out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn rfc7914_vectors() {
+        let mut out = [0u8; 64];
+        scrypt(b"", b"", &Params::new(4, 1, 1).unwrap(), &mut out);
+        assert_eq!(
+            &out[..],
+            &[
+                0x77, 0xd6, 0x57, 0x62, 0x38, 0x65, 0x7b, 0x20, 0x3b, 0x19, 0xca, 0x42, 0xc1, 0x8a,
+                0x04, 0x97, 0xf1, 0x6b, 0x48, 0x44, 0xe3, 0x07, 0x4a, 0xe8, 0xdf, 0xdf, 0xfa, 0x3f,
+                0xed, 0xe2, 0x14, 0x42, 0xfc, 0xd0, 0x06, 0x9d, 0xed, 0x09, 0x48, 0xf8, 0x32, 0x6a,
+                0x75, 0x3a, 0x0f, 0xc8, 0x1f, 0x17, 0xe8, 0xd3, 0xe0, 0xfb, 0x2e, 0x0d, 0x36, 0x28,
+                0xcf, 0x35, 0xe2, 0x0c, 0x38, 0xd1, 0x89, 0x06
+            ][..]
+        );
+        scrypt(
+            b"password",
+            b"NaCl",
+            &Params::new(10, 8, 16).unwrap(),
+            &mut out,
+        );
+        assert_eq!(
+            &out[..],
+            &[
+                0xfd, 0xba, 0xbe, 0x1c, 0x9d, 0x34, 0x72, 0x00, 0x78, 0x56, 0xe7, 0x19, 0x0d, 0x01,
+                0xe9, 0xfe, 0x7c, 0x6a, 0xd7, 0xcb, 0xc8, 0x23, 0x78, 0x30, 0xe7, 0x73, 0x76, 0x63,
+                0x4b, 0x37, 0x31, 0x62, 0x2e, 0xaf, 0x30, 0xd9, 0x2e, 0x22, 0xa3, 0x88, 0x6f, 0xf1,
+                0x09, 0x27, 0x9d, 0x98, 0x30, 0xda, 0xc7, 0x27, 0xaf, 0xb9, 0x4a, 0x83, 0xee, 0x6d,
+                0x83, 0x60, 0xcb, 0xdf, 0xa2, 0xcc, 0x06, 0x40
+            ][..]
+        );
+    }
+    #[test]
+    fn invalid_params() {
+        assert_eq!(Params::new(0, 8, 1), None);
+        assert_eq!(Params::new(15, 0, 1), None);
+        assert_eq!(Params::new(15, 8, 0), None);
+        assert_eq!(Params::new(16, 1, 1), None);
+        assert_eq!(Params::new(15, 1 << 15, 1 << 15), None);
+        // 128 * r * N does not fit in memory
+        assert_eq!(Params::new(60, 8, 1), None);
+        assert_eq!(Params::new(56, 8, 1), None);
+    }
+}
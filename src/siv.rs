@@ -0,0 +1,157 @@
+//! Nonce-misuse-resistant encryption using a synthetic IV
+//!
+//! Instead of taking a nonce from the caller, `seal_siv` computes it from the message: the
+//! synthetic IV is a keyed BLAKE2b-192 hash of the associated data and the plaintext, and the
+//! plaintext is then encrypted with XSalsa20 or XChacha20 under that IV. When opening, the
+//! plaintext is decrypted and the IV is recomputed and compared. The IV doubles as the
+//! authentication tag, so no Poly1305 tag is needed.
+//!
+//! Two independent keys are derived from the key of the `SecretBox` with `kdf::derive_from_key`,
+//! using the context `SBox-SIV`: subkey 0 keys the hash, subkey 1 is used for encryption.
+//!
+//! # Deterministic encryption
+//!
+//! Encryption is deterministic: sealing the same plaintext with the same key and associated data
+//! always gives the same box. An observer can therefore tell when a message is repeated, but
+//! learns nothing else, and there is no nonce that could be reused by accident. If repetitions
+//! must not be visible, put a random or unique value into the associated data; the construction
+//! stays secure even if that value does repeat.
+//!
+//! The format of a box is `IV (24 bytes) || ciphertext`. The hash input is `length of the
+//! associated data (8 bytes, little endian) || associated data || plaintext`.
+use crate::blake2b::Blake2b;
+use crate::{chacha20, kdf, salsa20, CipherType, SecretBox};
+
+/// Size of the synthetic IV
+pub const SIV_BYTES: usize = 24;
+
+/// Context used to derive the SIV subkeys
+const CONTEXT: [u8; 8] = *b"SBox-SIV";
+
+impl SecretBox {
+    /// Computes the synthetic IV of a message
+    fn siv(&self, ad: &[u8], message: &[u8]) -> [u8; SIV_BYTES] {
+        let mac_key = kdf::derive_from_key(&self.key, 0, CONTEXT);
+        let mut hash = Blake2b::new_keyed(SIV_BYTES, &mac_key);
+        hash.update(&(ad.len() as u64).to_le_bytes());
+        hash.update(ad);
+        hash.update(message);
+        let mut siv = [0u8; SIV_BYTES];
+        siv.copy_from_slice(&hash.finalize());
+        siv
+    }
+
+    /// Encrypts or decrypts data in place with the keystream for the given IV
+    fn siv_crypt(&self, siv: [u8; SIV_BYTES], data: &mut [u8]) {
+        let enc_key = kdf::derive_from_key(&self.key, 1, CONTEXT);
+        let (sub_key, nonce) = kdf::generate_subkey(siv, enc_key);
+        match self.cipher {
+            CipherType::Chacha20 => chacha20::XChacha20::new(sub_key, nonce, 0).crypt(data),
+            CipherType::Salsa20 => salsa20::XSalsa20::new(sub_key, nonce, 0).crypt(data),
+        };
+    }
+
+    /// Seals a message together with associated data, which is authenticated but not encrypted.
+    /// No nonce is needed; see the module documentation for the consequences.
+    pub fn seal_siv(&self, ad: &[u8], message: &[u8]) -> Vec<u8> {
+        let siv = self.siv(ad, message);
+        let mut out = Vec::with_capacity(SIV_BYTES + message.len());
+        out.extend_from_slice(&siv);
+        out.extend_from_slice(message);
+        self.siv_crypt(siv, &mut out[SIV_BYTES..]);
+        out
+    }
+
+    /// Opens a box created by `seal_siv` with the same associated data
+    ///
+    /// Returns None if the box is too short or not authentic
+    pub fn open_siv(&self, ad: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < SIV_BYTES {
+            return None;
+        }
+        let mut siv = [0u8; SIV_BYTES];
+        siv.copy_from_slice(&data[..SIV_BYTES]);
+        let mut message = data[SIV_BYTES..].to_vec();
+        self.siv_crypt(siv, &mut message);
+        if !crate::constant_time_eq(&self.siv(ad, &message), &siv) {
+            return None;
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn vectors() {
+        let key: Vec<u8> = (0..32).collect();
+        let message =
+            b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip";
+        let vectors = [
+            (
+                CipherType::Salsa20,
+                &b""[..],
+                &b""[..],
+                "24506c1d9340d40255138c872163fec351c0e1f2348a8cf4",
+            ),
+            (
+                CipherType::Salsa20,
+                &b"header"[..],
+                &message[..],
+                concat!(
+                    "2bda4fbb3dbe494595b3588422f4b5757e0ac12c9bc492f9420acf3e172cb495",
+                    "feece4095dd18912bf40eb4b9f722f406f9d1e1ff8bfeb37243e4001506bf4ba",
+                    "4f6e42949162a546bec285f853e2c8d80db0277dd1e466edf5d1c274ecc13eaa",
+                    "c347ca",
+                ),
+            ),
+            (
+                CipherType::Chacha20,
+                &b"header"[..],
+                &message[..],
+                concat!(
+                    "2bda4fbb3dbe494595b3588422f4b5757e0ac12c9bc492f94d105690dfa67ba2",
+                    "2565e27f32e294fad082e76571813ee6496d59c8d004e370749c15a0eb0352a2",
+                    "c338b340a569d198d723f171c5e5ebed1cf75bf9f08484cfd63ee16d1104e31f",
+                    "c1b0eb",
+                ),
+            ),
+        ];
+        for &(cipher, ad, message, expected) in vectors.iter() {
+            let s = SecretBox::new(&key, cipher).unwrap();
+            let sealed = s.seal_siv(ad, message);
+            assert_eq!(sealed, unhex(expected));
+            assert_eq!(s.open_siv(ad, &sealed).unwrap(), message.to_vec());
+        }
+    }
+
+    #[test]
+    fn misuse() {
+        let s = SecretBox::new([7u8; 32], CipherType::Salsa20).unwrap();
+        let a = s.seal_siv(b"ad", b"attack at dawn");
+        assert_eq!(s.seal_siv(b"ad", b"attack at dawn"), a);
+        assert_ne!(s.seal_siv(b"ad", b"attack at dusk"), a);
+        assert_ne!(s.seal_siv(b"da", b"attack at dawn"), a);
+        // The length prefix separates the associated data from the message
+        assert_ne!(s.seal_siv(b"ad", b"x"), s.seal_siv(b"adx", b""));
+
+        assert_eq!(s.open_siv(b"other", &a), None);
+        for i in 0..a.len() {
+            let mut forged = a.clone();
+            forged[i] ^= 1;
+            assert_eq!(s.open_siv(b"ad", &forged), None);
+        }
+        assert_eq!(s.open_siv(b"ad", &a[..23]), None);
+        let other = SecretBox::new([8u8; 32], CipherType::Salsa20).unwrap();
+        assert_eq!(other.open_siv(b"ad", &a), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Sealing messages that are split over several buffers
+//!
+//! `seal_vectored` seals the concatenation of several buffers without concatenating them first,
+//! and `open_vectored` scatters the plaintext of a box over several buffers. The buffers can have
+//! any length; the keystream and the Poly1305 input are buffered across buffer boundaries.
+use crate::poly1305::Poly1305;
+use crate::{poly1305_from_key, Keystream, SecretBox};
+use std::io::{IoSlice, IoSliceMut};
+
+/// Keystream that can be applied to pieces of any length
+struct Xor {
+    keystream: Keystream,
+    block: Vec<u8>,
+    used: usize,
+}
+
+impl Xor {
+    fn apply(&mut self, mut data: &mut [u8]) {
+        while !data.is_empty() {
+            if self.used == self.block.len() {
+                self.block = self.keystream.generate_block();
+                self.used = 0;
+            }
+            let n = std::cmp::min(data.len(), self.block.len() - self.used);
+            let (head, tail) = data.split_at_mut(n);
+            for (d, k) in head.iter_mut().zip(&self.block[self.used..]) {
+                *d ^= k;
+            }
+            self.used += n;
+            data = tail;
+        }
+    }
+}
+
+/// Poly1305 that can be fed pieces of any length
+struct Mac {
+    mac: Poly1305,
+    partial: [u8; 16],
+    len: usize,
+}
+
+impl Mac {
+    fn update(&mut self, mut data: &[u8]) {
+        if self.len > 0 {
+            let n = std::cmp::min(data.len(), 16 - self.len);
+            self.partial[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+            if self.len < 16 {
+                return;
+            }
+            self.mac.read_block(&self.partial);
+            self.len = 0;
+        }
+        let aligned = data.len() / 16 * 16;
+        self.mac.update(&data[..aligned]);
+        self.len = data.len() - aligned;
+        self.partial[..self.len].copy_from_slice(&data[aligned..]);
+    }
+    fn finalize(mut self) -> [u8; 16] {
+        if self.len > 0 {
+            self.mac.read_block(&self.partial[..self.len]);
+        }
+        self.mac.finalize().to_le_bytes()
+    }
+}
+
+impl SecretBox {
+    /// Sets up the keystream, positioned after the Poly1305 key, and the Poly1305 instance
+    fn vectored_state(&self, nonce: [u8; 24]) -> (Xor, Mac) {
+        let (sub_key, nonce, initial_block) = self.first_block(nonce);
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&initial_block[..32]);
+        let xor = Xor {
+            keystream: self.keystream(sub_key, nonce),
+            block: initial_block.to_vec(),
+            used: 32,
+        };
+        let mac = Mac {
+            mac: poly1305_from_key(key),
+            partial: [0u8; 16],
+            len: 0,
+        };
+        (xor, mac)
+    }
+
+    /// Seals the concatenation of the buffers. The output is identical to calling `seal` with
+    /// the concatenated message.
+    pub fn seal_vectored(&self, bufs: &[IoSlice], nonce: [u8; 24]) -> Vec<u8> {
+        let total: usize = bufs.iter().map(|b| b.len()).sum();
+        let mut out = Vec::with_capacity(total + 16);
+        out.extend_from_slice(&[0u8; 16]);
+        let (mut xor, mut mac) = self.vectored_state(nonce);
+        for buf in bufs {
+            let start = out.len();
+            out.extend_from_slice(buf);
+            xor.apply(&mut out[start..]);
+            mac.update(&out[start..]);
+        }
+        let tag = mac.finalize();
+        out[..16].copy_from_slice(&tag);
+        out
+    }
+
+    /// Opens a box and writes the plaintext into the buffers, filling each buffer before moving
+    /// on to the next one. The box is authenticated before anything is written.
+    ///
+    /// Returns the length of the plaintext, or None if the box is not authentic or does not fit
+    /// into the buffers.
+    pub fn open_vectored(
+        &self,
+        data: &[u8],
+        nonce: [u8; 24],
+        bufs: &mut [IoSliceMut],
+    ) -> Option<usize> {
+        if data.len() < 16 {
+            return None;
+        }
+        let (tag, mut ciphertext) = data.split_at(16);
+        let len = ciphertext.len();
+        if bufs.iter().map(|b| b.len()).sum::<usize>() < len {
+            return None;
+        }
+        let (mut xor, mut mac) = self.vectored_state(nonce);
+        mac.update(ciphertext);
+        if !crate::constant_time_eq(&mac.finalize(), tag) {
+            return None;
+        }
+        for buf in bufs.iter_mut() {
+            let n = std::cmp::min(buf.len(), ciphertext.len());
+            buf[..n].copy_from_slice(&ciphertext[..n]);
+            xor.apply(&mut buf[..n]);
+            ciphertext = &ciphertext[n..];
+        }
+        Some(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CipherType;
+
+    #[test]
+    fn vectored() {
+        let s = SecretBox::new([6u8; 32], CipherType::Salsa20).unwrap();
+        let message: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+        let sealed = s.seal(&message, [1u8; 24]);
+        for &splits in [
+            &[0, 300][..],
+            &[5, 17, 33, 100, 250],
+            &[31, 32, 64, 65, 299],
+        ]
+        .iter()
+        {
+            let mut bufs = Vec::new();
+            let mut last = 0;
+            for &split in splits.iter().chain([300].iter()) {
+                bufs.push(IoSlice::new(&message[last..split]));
+                last = split;
+            }
+            assert_eq!(s.seal_vectored(&bufs, [1u8; 24]), sealed);
+
+            let mut out = vec![0u8; 310];
+            let mut pieces = Vec::new();
+            let mut rest = &mut out[..];
+            let mut last = 0;
+            for &split in splits.iter() {
+                let (head, tail) = rest.split_at_mut(split - last);
+                pieces.push(IoSliceMut::new(head));
+                rest = tail;
+                last = split;
+            }
+            pieces.push(IoSliceMut::new(rest));
+            assert_eq!(s.open_vectored(&sealed, [1u8; 24], &mut pieces), Some(300));
+            assert_eq!(&out[..300], &message[..]);
+        }
+
+        let mut small = [0u8; 299];
+        let mut bufs = [IoSliceMut::new(&mut small)];
+        assert_eq!(s.open_vectored(&sealed, [1u8; 24], &mut bufs), None);
+        let mut forged = sealed;
+        forged[200] ^= 1;
+        let mut out = [0u8; 300];
+        let mut bufs = [IoSliceMut::new(&mut out)];
+        assert_eq!(s.open_vectored(&forged, [1u8; 24], &mut bufs), None);
+        assert_eq!(&out[..], &[0u8; 300][..]);
+    }
+}
@@ -0,0 +1,150 @@
+//! Wrapping data keys under a key-encryption key
+//!
+//! A data key is wrapped with the SIV mode of the key-encryption key (see the `siv` module), so no
+//! nonce is needed and wrapping the same key twice gives the same result. The algorithm ID is used
+//! as associated data, so a wrapped key cannot be passed off as one wrapped with another cipher.
+//!
+//! The serialized form of a wrapped key is `algorithm ID (1 byte, see CipherType::id) || SIV (24
+//! bytes) || encrypted key (32 bytes)`.
+use crate::siv::SIV_BYTES;
+use crate::{CipherType, SecretBox};
+use std::fmt;
+
+/// Data key
+pub type Key = [u8; 32];
+
+/// Size of a serialized wrapped key
+pub const WRAPPED_KEY_BYTES: usize = 1 + SIV_BYTES + 32;
+
+/// Errors that can occur while parsing or unwrapping a wrapped key
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WrapError {
+    /// The serialized key has the wrong length
+    InvalidLength(usize),
+    /// The key was wrapped with an unknown algorithm
+    UnknownAlgorithm(u8),
+    /// The key could not be authenticated with the given key-encryption key
+    Unauthentic,
+}
+
+impl fmt::Display for WrapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WrapError::InvalidLength(l) => write!(f, "wrapped key has invalid length {}", l),
+            WrapError::UnknownAlgorithm(a) => write!(f, "unknown algorithm ID {}", a),
+            WrapError::Unauthentic => write!(f, "wrapped key could not be authenticated"),
+        }
+    }
+}
+
+impl std::error::Error for WrapError {}
+
+/// Data key encrypted under a key-encryption key
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WrappedKey {
+    cipher: CipherType,
+    sealed: [u8; SIV_BYTES + 32],
+}
+
+impl WrappedKey {
+    /// Returns the cipher the key was wrapped with
+    pub fn cipher(&self) -> CipherType {
+        self.cipher
+    }
+    /// Parses a serialized wrapped key
+    pub fn from_bytes(data: &[u8]) -> Result<Self, WrapError> {
+        if data.len() != WRAPPED_KEY_BYTES {
+            return Err(WrapError::InvalidLength(data.len()));
+        }
+        let cipher = CipherType::from_id(data[0]).ok_or(WrapError::UnknownAlgorithm(data[0]))?;
+        let mut sealed = [0u8; SIV_BYTES + 32];
+        sealed.copy_from_slice(&data[1..]);
+        Ok(Self { cipher, sealed })
+    }
+    /// Serializes the wrapped key
+    pub fn to_bytes(&self) -> [u8; WRAPPED_KEY_BYTES] {
+        let mut out = [0u8; WRAPPED_KEY_BYTES];
+        out[0] = self.cipher.id();
+        out[1..].copy_from_slice(&self.sealed);
+        out
+    }
+}
+
+impl SecretBox {
+    /// Wraps a data key, using this box as the key-encryption key
+    pub fn wrap_key(&self, key: &Key) -> WrappedKey {
+        let mut sealed = [0u8; SIV_BYTES + 32];
+        sealed.copy_from_slice(&self.seal_siv(&[self.cipher.id()], key));
+        WrappedKey {
+            cipher: self.cipher,
+            sealed,
+        }
+    }
+    /// Unwraps a data key with the cipher it was wrapped with
+    pub fn unwrap_key(&self, wrapped: &WrappedKey) -> Result<Key, WrapError> {
+        let kek = SecretBox {
+            key: self.key,
+            cipher: wrapped.cipher,
+        };
+        let unwrapped = kek
+            .open_siv(&[wrapped.cipher.id()], &wrapped.sealed)
+            .ok_or(WrapError::Unauthentic)?;
+        // The length of the ciphertext is fixed by `WrappedKey`, so the plaintext is a whole key
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&unwrapped);
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_unwrap() {
+        let kek = SecretBox::new([5u8; 32], CipherType::Chacha20).unwrap();
+        let data_key = [0x11u8; 32];
+        let wrapped = kek.wrap_key(&data_key);
+        assert_eq!(wrapped, kek.wrap_key(&data_key));
+        assert_ne!(wrapped, kek.wrap_key(&[0x12u8; 32]));
+        let bytes = wrapped.to_bytes();
+        assert_eq!(bytes[0], CipherType::Chacha20.id());
+        assert_eq!(&bytes[1..], &kek.seal_siv(&bytes[..1], &data_key)[..]);
+        let parsed = WrappedKey::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.cipher(), CipherType::Chacha20);
+        assert_eq!(kek.unwrap_key(&parsed), Ok(data_key));
+
+        // The algorithm ID in the wrapped key selects the cipher
+        let salsa20 = SecretBox::new([5u8; 32], CipherType::Salsa20).unwrap();
+        assert_eq!(salsa20.unwrap_key(&parsed), Ok(data_key));
+        let other = SecretBox::new([6u8; 32], CipherType::Chacha20).unwrap();
+        assert_eq!(other.unwrap_key(&parsed), Err(WrapError::Unauthentic));
+    }
+
+    #[test]
+    fn errors() {
+        let kek = SecretBox::new([5u8; 32], CipherType::Salsa20).unwrap();
+        let bytes = kek.wrap_key(&[0x11u8; 32]).to_bytes();
+        assert_eq!(
+            WrappedKey::from_bytes(&bytes[..56]),
+            Err(WrapError::InvalidLength(56))
+        );
+        assert_eq!(
+            WrappedKey::from_bytes(&[0u8; 58]),
+            Err(WrapError::InvalidLength(58))
+        );
+        let mut bad = bytes;
+        bad[0] = 0xff;
+        assert_eq!(
+            WrappedKey::from_bytes(&bad),
+            Err(WrapError::UnknownAlgorithm(0xff))
+        );
+        for i in 0..WRAPPED_KEY_BYTES {
+            let mut forged = bytes;
+            forged[i] ^= 1;
+            if let Ok(wrapped) = WrappedKey::from_bytes(&forged) {
+                assert_eq!(kek.unwrap_key(&wrapped), Err(WrapError::Unauthentic));
+            }
+        }
+    }
+}